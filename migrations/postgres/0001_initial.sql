@@ -1,15 +1,16 @@
+-- Every statement tolerates existing objects so databases created by the old setup script can adopt migrations
 CREATE TABLE IF NOT EXISTS system_prompts (
     id BIGSERIAL PRIMARY KEY,
     name TEXT NOT NULL,
     contents TEXT NOT NULL
 );
--- break
+
 INSERT INTO system_prompts (id, name, contents)
 VALUES
     (0, 'default', 'You are Lumi, a helpful assistant.'),
     (1, 'social', 'Read each message and decide if it would be socially expected for the user Lumi to respond to each message. Responses must always be valid JSON matching the schema `{"should_reply":true|false}`.')
 ON CONFLICT (id) DO NOTHING;
--- break
+
 DO $$
 BEGIN
     IF NOT EXISTS (
@@ -23,7 +24,7 @@ BEGIN
     END IF;
 END
 $$;
--- break
+
 CREATE TABLE IF NOT EXISTS channels (
     id BIGINT PRIMARY KEY,
     chat_mode chat_mode NOT NULL DEFAULT 'mentions_only_all_context',
@@ -32,11 +33,11 @@ CREATE TABLE IF NOT EXISTS channels (
     CONSTRAINT fk_system_promt
         FOREIGN KEY (system_prompt) REFERENCES system_prompts(id)
 );
--- break
+
 CREATE TABLE IF NOT EXISTS messages (
     id BIGINT PRIMARY KEY,
     is_self BOOLEAN NOT NULL,
-    mentions_me BOOLEAN NOT NULL,
+    mentions_self BOOLEAN NOT NULL,
     sender BIGINT NOT NULL,
     sender_name TEXT NOT NULL,
     sender_display_name TEXT NOT NULL,
@@ -51,3 +52,17 @@ CREATE TABLE IF NOT EXISTS messages (
         FOREIGN KEY (reply) REFERENCES messages(id)
         ON DELETE SET NULL
 );
+
+CREATE TABLE IF NOT EXISTS attachments (
+    id BIGINT PRIMARY KEY,
+    message BIGINT NOT NULL,
+    filename TEXT NOT NULL,
+    url TEXT NOT NULL,
+    content_type TEXT,
+    description TEXT,
+    CONSTRAINT fk_message
+        FOREIGN KEY (message) REFERENCES messages(id)
+        ON DELETE CASCADE
+);
+
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS deleted BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,12 @@
+-- Databases created by the old setup script named this column mentions_me
+DO $$
+BEGIN
+    IF EXISTS (
+        SELECT 1
+        FROM information_schema.columns
+        WHERE table_name = 'messages' AND column_name = 'mentions_me'
+    ) THEN
+        ALTER TABLE messages RENAME COLUMN mentions_me TO mentions_self;
+    END IF;
+END
+$$;
@@ -0,0 +1,18 @@
+-- Prompts used to be inserted by hand, so names may already repeat. Every duplicate but the oldest
+-- gets its id appended to its name
+UPDATE system_prompts
+SET name = name || ' (' || id || ')'
+WHERE EXISTS (
+    SELECT 1
+    FROM system_prompts other
+    WHERE other.name = system_prompts.name
+        AND other.id < system_prompts.id
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS system_prompts_name_key ON system_prompts (name);
+
+-- The built-in prompts were inserted with explicit ids, so the sequence has to skip past them
+SELECT setval(
+    pg_get_serial_sequence('system_prompts', 'id'),
+    GREATEST((SELECT MAX(id) FROM system_prompts), 1)
+);
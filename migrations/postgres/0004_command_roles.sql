@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS command_roles (
+    guild BIGINT NOT NULL,
+    role BIGINT NOT NULL,
+    PRIMARY KEY (guild, role)
+);
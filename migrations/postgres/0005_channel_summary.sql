@@ -0,0 +1,2 @@
+-- Rolling summary of the messages that fell out of the context window
+ALTER TABLE channels ADD COLUMN IF NOT EXISTS summary TEXT;
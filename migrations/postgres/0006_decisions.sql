@@ -0,0 +1,11 @@
+-- Why the social model decided to reply to a message or stay silent
+CREATE TABLE IF NOT EXISTS decisions (
+    message BIGINT PRIMARY KEY,
+    channel BIGINT NOT NULL,
+    model TEXT,
+    should_reply BOOLEAN NOT NULL,
+    confidence DOUBLE PRECISION,
+    reason TEXT,
+    latency BIGINT,
+    time BIGINT NOT NULL DEFAULT extract(epoch from now())::bigint
+);
@@ -0,0 +1,13 @@
+-- Tokens consumed by every completion, attributed to the user whose message caused it
+CREATE TABLE IF NOT EXISTS usage (
+    id BIGSERIAL PRIMARY KEY,
+    guild BIGINT,
+    channel BIGINT NOT NULL,
+    sender BIGINT,
+    model TEXT NOT NULL,
+    prompt_tokens BIGINT NOT NULL,
+    completion_tokens BIGINT NOT NULL,
+    time BIGINT NOT NULL DEFAULT extract(epoch from now())::bigint
+);
+
+CREATE INDEX IF NOT EXISTS usage_time_idx ON usage (time);
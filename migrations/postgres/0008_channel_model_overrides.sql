@@ -0,0 +1,7 @@
+-- Per-channel overrides of the chat model's settings, NULL falls back to the config
+ALTER TABLE channels
+    ADD COLUMN IF NOT EXISTS model TEXT,
+    ADD COLUMN IF NOT EXISTS temperature DOUBLE PRECISION,
+    ADD COLUMN IF NOT EXISTS top_p DOUBLE PRECISION,
+    ADD COLUMN IF NOT EXISTS max_tokens BIGINT,
+    ADD COLUMN IF NOT EXISTS reasoning TEXT;
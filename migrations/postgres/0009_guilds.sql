@@ -0,0 +1,13 @@
+-- Per-guild defaults, NULL falls back to the config or the built-in defaults
+CREATE TABLE IF NOT EXISTS guilds (
+    id BIGINT PRIMARY KEY,
+    chat_mode chat_mode,
+    system_prompt BIGINT,
+    model TEXT,
+    user_messages_per_minute BIGINT,
+    channel_completions_per_hour BIGINT,
+    daily_tokens BIGINT,
+    daily_cost DOUBLE PRECISION,
+    CONSTRAINT fk_system_prompt
+        FOREIGN KEY (system_prompt) REFERENCES system_prompts(id)
+);
@@ -0,0 +1,3 @@
+-- Threads point at the channel they were started in, their id is also the id of their starter message
+ALTER TABLE channels ADD COLUMN IF NOT EXISTS parent BIGINT
+    REFERENCES channels(id) ON DELETE SET NULL;
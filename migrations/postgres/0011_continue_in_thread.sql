@@ -0,0 +1 @@
+ALTER TYPE chat_mode ADD VALUE IF NOT EXISTS 'continue_in_thread';
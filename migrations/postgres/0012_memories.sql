@@ -0,0 +1,10 @@
+-- Facts Lumi remembers about a user, kept per guild so they don't follow the user across servers
+CREATE TABLE IF NOT EXISTS memories (
+    id BIGSERIAL PRIMARY KEY,
+    subject BIGINT NOT NULL,
+    guild BIGINT,
+    contents TEXT NOT NULL,
+    time BIGINT NOT NULL DEFAULT extract(epoch from now())::bigint
+);
+
+CREATE INDEX IF NOT EXISTS memories_subject_idx ON memories (subject, guild);
@@ -0,0 +1,4 @@
+-- Recall never reaches back past the last /reset_context. Channels reset before this existed can't
+-- be told apart from ones whose window just moved, so they start out at their context window
+ALTER TABLE channels ADD COLUMN IF NOT EXISTS recall_window BIGINT NOT NULL DEFAULT 0;
+UPDATE channels SET recall_window = context_window;
@@ -0,0 +1,3 @@
+-- What each request was made for, channel limits only count chat and social completions. Earlier
+-- rows can't be told apart and count as chat completions
+ALTER TABLE usage ADD COLUMN kind TEXT NOT NULL DEFAULT 'chat';
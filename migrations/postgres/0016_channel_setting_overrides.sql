@@ -0,0 +1,39 @@
+-- Channels only keep the chat mode and system prompt they picked themselves, anything else is
+-- looked up through their parent and guild when read, so later server defaults still reach them
+ALTER TABLE channels ADD COLUMN IF NOT EXISTS guild BIGINT;
+UPDATE channels
+SET guild = (
+    SELECT m.guild
+    FROM messages m
+    WHERE m.channel = channels.id
+        AND m.guild IS NOT NULL
+    LIMIT 1
+)
+WHERE guild IS NULL;
+UPDATE channels
+SET guild = (SELECT p.guild FROM channels p WHERE p.id = channels.parent)
+WHERE guild IS NULL
+    AND parent IS NOT NULL;
+
+ALTER TABLE channels
+    ALTER COLUMN chat_mode DROP NOT NULL,
+    ALTER COLUMN chat_mode DROP DEFAULT,
+    ALTER COLUMN system_prompt DROP NOT NULL,
+    ALTER COLUMN system_prompt DROP DEFAULT;
+
+-- Settings that match what a channel would inherit were most likely inherited. Threads keep theirs,
+-- they were copied from their parent as it was when the thread started
+UPDATE channels
+SET chat_mode = NULL
+WHERE parent IS NULL
+    AND chat_mode = COALESCE(
+        (SELECT g.chat_mode FROM guilds g WHERE g.id = channels.guild),
+        'mentions_only_all_context'
+    );
+UPDATE channels
+SET system_prompt = NULL
+WHERE parent IS NULL
+    AND system_prompt = COALESCE(
+        (SELECT g.system_prompt FROM guilds g WHERE g.id = channels.guild),
+        0
+    );
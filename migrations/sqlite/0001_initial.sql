@@ -0,0 +1,53 @@
+CREATE TABLE IF NOT EXISTS system_prompts (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL,
+    contents TEXT NOT NULL
+);
+
+INSERT INTO system_prompts (id, name, contents)
+VALUES
+    (0, 'default', 'You are Lumi, a helpful assistant.'),
+    (1, 'social', 'Read each message and decide if it would be socially expected for the user Lumi to respond to each message. Responses must always be valid JSON matching the schema `{"should_reply":true|false}`.')
+ON CONFLICT (id) DO NOTHING;
+
+CREATE TABLE IF NOT EXISTS channels (
+    id INTEGER PRIMARY KEY,
+    chat_mode TEXT NOT NULL DEFAULT 'mentions_only_all_context'
+        CHECK (chat_mode IN ('free_response', 'mentions_only', 'mentions_only_all_context')),
+    context_window INTEGER NOT NULL DEFAULT (unixepoch()),
+    system_prompt INTEGER NOT NULL DEFAULT 0,
+    CONSTRAINT fk_system_promt
+        FOREIGN KEY (system_prompt) REFERENCES system_prompts(id)
+);
+
+CREATE TABLE IF NOT EXISTS messages (
+    id INTEGER PRIMARY KEY,
+    is_self BOOLEAN NOT NULL,
+    mentions_self BOOLEAN NOT NULL,
+    sender INTEGER NOT NULL,
+    sender_name TEXT NOT NULL,
+    sender_display_name TEXT NOT NULL,
+    guild INTEGER,
+    channel INTEGER NOT NULL,
+    contents TEXT NOT NULL,
+    reply INTEGER,
+    time INTEGER NOT NULL DEFAULT (unixepoch()),
+    deleted BOOLEAN NOT NULL DEFAULT false,
+    CONSTRAINT fk_channel
+        FOREIGN KEY (channel) REFERENCES channels(id),
+    CONSTRAINT fk_reply
+        FOREIGN KEY (reply) REFERENCES messages(id)
+        ON DELETE SET NULL
+);
+
+CREATE TABLE IF NOT EXISTS attachments (
+    id INTEGER PRIMARY KEY,
+    message INTEGER NOT NULL,
+    filename TEXT NOT NULL,
+    url TEXT NOT NULL,
+    content_type TEXT,
+    description TEXT,
+    CONSTRAINT fk_message
+        FOREIGN KEY (message) REFERENCES messages(id)
+        ON DELETE CASCADE
+);
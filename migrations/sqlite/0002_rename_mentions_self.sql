@@ -0,0 +1,2 @@
+-- SQLite databases have always been created with the mentions_self column
+SELECT 1;
@@ -0,0 +1,12 @@
+-- Prompts used to be inserted by hand, so names may already repeat. Every duplicate but the oldest
+-- gets its id appended to its name
+UPDATE system_prompts
+SET name = name || ' (' || id || ')'
+WHERE EXISTS (
+    SELECT 1
+    FROM system_prompts other
+    WHERE other.name = system_prompts.name
+        AND other.id < system_prompts.id
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS system_prompts_name_key ON system_prompts (name);
@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS command_roles (
+    guild INTEGER NOT NULL,
+    role INTEGER NOT NULL,
+    PRIMARY KEY (guild, role)
+);
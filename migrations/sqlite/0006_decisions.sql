@@ -0,0 +1,11 @@
+-- Why the social model decided to reply to a message or stay silent
+CREATE TABLE IF NOT EXISTS decisions (
+    message INTEGER PRIMARY KEY,
+    channel INTEGER NOT NULL,
+    model TEXT,
+    should_reply BOOLEAN NOT NULL,
+    confidence REAL,
+    reason TEXT,
+    latency INTEGER,
+    time INTEGER NOT NULL DEFAULT (unixepoch())
+);
@@ -0,0 +1,13 @@
+-- Tokens consumed by every completion, attributed to the user whose message caused it
+CREATE TABLE IF NOT EXISTS usage (
+    id INTEGER PRIMARY KEY,
+    guild INTEGER,
+    channel INTEGER NOT NULL,
+    sender INTEGER,
+    model TEXT NOT NULL,
+    prompt_tokens INTEGER NOT NULL,
+    completion_tokens INTEGER NOT NULL,
+    time INTEGER NOT NULL DEFAULT (unixepoch())
+);
+
+CREATE INDEX IF NOT EXISTS usage_time_idx ON usage (time);
@@ -0,0 +1,6 @@
+-- Per-channel overrides of the chat model's settings, NULL falls back to the config
+ALTER TABLE channels ADD COLUMN model TEXT;
+ALTER TABLE channels ADD COLUMN temperature REAL;
+ALTER TABLE channels ADD COLUMN top_p REAL;
+ALTER TABLE channels ADD COLUMN max_tokens INTEGER;
+ALTER TABLE channels ADD COLUMN reasoning TEXT;
@@ -0,0 +1,14 @@
+-- Per-guild defaults, NULL falls back to the config or the built-in defaults
+CREATE TABLE IF NOT EXISTS guilds (
+    id INTEGER PRIMARY KEY,
+    chat_mode TEXT
+        CHECK (chat_mode IN ('free_response', 'mentions_only', 'mentions_only_all_context')),
+    system_prompt INTEGER,
+    model TEXT,
+    user_messages_per_minute INTEGER,
+    channel_completions_per_hour INTEGER,
+    daily_tokens INTEGER,
+    daily_cost REAL,
+    CONSTRAINT fk_system_prompt
+        FOREIGN KEY (system_prompt) REFERENCES system_prompts(id)
+);
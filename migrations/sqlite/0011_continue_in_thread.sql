@@ -0,0 +1,57 @@
+-- SQLite can't alter CHECK constraints, so both tables are rebuilt. Foreign keys can't be turned off
+-- inside the migration's transaction, deferring them lets messages point at the rebuilt channels
+PRAGMA defer_foreign_keys = ON;
+
+CREATE TEMP TABLE channels_backup AS SELECT * FROM channels;
+DROP TABLE channels;
+CREATE TABLE channels (
+    id INTEGER PRIMARY KEY,
+    chat_mode TEXT NOT NULL DEFAULT 'mentions_only_all_context'
+        CHECK (chat_mode IN ('free_response', 'mentions_only', 'mentions_only_all_context', 'continue_in_thread')),
+    context_window INTEGER NOT NULL DEFAULT (unixepoch()),
+    system_prompt INTEGER NOT NULL DEFAULT 0,
+    summary TEXT,
+    model TEXT,
+    temperature REAL,
+    top_p REAL,
+    max_tokens INTEGER,
+    reasoning TEXT,
+    parent INTEGER
+        REFERENCES channels(id) ON DELETE SET NULL,
+    CONSTRAINT fk_system_promt
+        FOREIGN KEY (system_prompt) REFERENCES system_prompts(id)
+);
+INSERT INTO channels (
+    id, chat_mode, context_window, system_prompt, summary, model, temperature, top_p, max_tokens,
+    reasoning, parent
+)
+SELECT
+    id, chat_mode, context_window, system_prompt, summary, model, temperature, top_p, max_tokens,
+    reasoning, parent
+FROM channels_backup;
+DROP TABLE channels_backup;
+
+CREATE TEMP TABLE guilds_backup AS SELECT * FROM guilds;
+DROP TABLE guilds;
+CREATE TABLE guilds (
+    id INTEGER PRIMARY KEY,
+    chat_mode TEXT
+        CHECK (chat_mode IN ('free_response', 'mentions_only', 'mentions_only_all_context', 'continue_in_thread')),
+    system_prompt INTEGER,
+    model TEXT,
+    user_messages_per_minute INTEGER,
+    channel_completions_per_hour INTEGER,
+    daily_tokens INTEGER,
+    daily_cost REAL,
+    CONSTRAINT fk_system_prompt
+        FOREIGN KEY (system_prompt) REFERENCES system_prompts(id)
+);
+INSERT INTO guilds (
+    id, chat_mode, system_prompt, model, user_messages_per_minute, channel_completions_per_hour,
+    daily_tokens, daily_cost
+)
+SELECT
+    id, chat_mode, system_prompt, model, user_messages_per_minute, channel_completions_per_hour,
+    daily_tokens, daily_cost
+FROM guilds_backup;
+DROP TABLE guilds_backup;
@@ -0,0 +1,10 @@
+-- Facts Lumi remembers about a user, kept per guild so they don't follow the user across servers
+CREATE TABLE IF NOT EXISTS memories (
+    id INTEGER PRIMARY KEY,
+    subject INTEGER NOT NULL,
+    guild INTEGER,
+    contents TEXT NOT NULL,
+    time INTEGER NOT NULL DEFAULT (unixepoch())
+);
+
+CREATE INDEX IF NOT EXISTS memories_subject_idx ON memories (subject, guild);
@@ -0,0 +1,13 @@
+-- Embeddings of stored messages for recalling older history, normalized so their dot product is
+-- the cosine similarity. Stored as little endian f32s
+CREATE TABLE IF NOT EXISTS embeddings (
+    message INTEGER PRIMARY KEY,
+    channel INTEGER NOT NULL,
+    model TEXT NOT NULL,
+    embedding BLOB NOT NULL,
+    CONSTRAINT fk_message
+        FOREIGN KEY (message) REFERENCES messages(id)
+        ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS embeddings_channel_idx ON embeddings (channel, model);
@@ -0,0 +1,63 @@
+-- Channels only keep the chat mode and system prompt they picked themselves, anything else is
+-- looked up through their parent and guild when read, so later server defaults still reach them.
+-- SQLite can't drop NOT NULL constraints, so the table is rebuilt like in 0011
+PRAGMA defer_foreign_keys = ON;
+
+CREATE TEMP TABLE channels_backup AS SELECT * FROM channels;
+DROP TABLE channels;
+CREATE TABLE channels (
+    id INTEGER PRIMARY KEY,
+    chat_mode TEXT
+        CHECK (chat_mode IN ('free_response', 'mentions_only', 'mentions_only_all_context', 'continue_in_thread')),
+    context_window INTEGER NOT NULL DEFAULT (unixepoch()),
+    system_prompt INTEGER,
+    summary TEXT,
+    model TEXT,
+    temperature REAL,
+    top_p REAL,
+    max_tokens INTEGER,
+    reasoning TEXT,
+    parent INTEGER
+        REFERENCES channels(id) ON DELETE SET NULL,
+    recall_window INTEGER NOT NULL DEFAULT 0,
+    guild INTEGER,
+    CONSTRAINT fk_system_prompt
+        FOREIGN KEY (system_prompt) REFERENCES system_prompts(id)
+);
+INSERT INTO channels (
+    id, chat_mode, context_window, system_prompt, summary, model, temperature, top_p, max_tokens,
+    reasoning, parent, recall_window, guild
+)
+SELECT
+    b.id, b.chat_mode, b.context_window, b.system_prompt, b.summary, b.model, b.temperature,
+    b.top_p, b.max_tokens, b.reasoning, b.parent, b.recall_window,
+    (
+        SELECT m.guild
+        FROM messages m
+        WHERE m.channel = b.id
+            AND m.guild IS NOT NULL
+        LIMIT 1
+    )
+FROM channels_backup b;
+DROP TABLE channels_backup;
+UPDATE channels
+SET guild = (SELECT p.guild FROM channels p WHERE p.id = channels.parent)
+WHERE guild IS NULL
+    AND parent IS NOT NULL;
+
+-- Settings that match what a channel would inherit were most likely inherited. Threads keep theirs,
+-- they were copied from their parent as it was when the thread started
+UPDATE channels
+SET chat_mode = NULL
+WHERE parent IS NULL
+    AND chat_mode = COALESCE(
+        (SELECT g.chat_mode FROM guilds g WHERE g.id = channels.guild),
+        'mentions_only_all_context'
+    );
+UPDATE channels
+SET system_prompt = NULL
+WHERE parent IS NULL
+    AND system_prompt = COALESCE(
+        (SELECT g.system_prompt FROM guilds g WHERE g.id = channels.guild),
+        0
+    );
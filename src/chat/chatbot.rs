@@ -1,18 +1,26 @@
-use indoc::indoc;
-use openai_api_rs::v1::{api::OpenAIClient, chat_completion::*};
+use std::time::Duration;
+
+use openai_api_rs::v1::chat_completion::{Content as OpenAIContent, *};
 use serenity::all::{Message as SerenityMessage, *};
-use sqlx::{Postgres, Transaction};
-use tokio::sync::{Mutex, RwLock};
+use tokio::{sync::RwLock, time::Instant};
 
 use crate::{
     Config,
-    chat::{context, social},
-    db::ChatMode,
+    chat::{
+        client::{LlmClient, ModelChain, Scope},
+        context, social, split, tokens, tools,
+    },
+    db::{self, ChatMode},
 };
 
-pub async fn generate<'d>(
-    transaction: &mut Transaction<'d, Postgres>,
-    openai: &Mutex<OpenAIClient>,
+const STREAM_PLACEHOLDER: &str = "-# Thinking...";
+
+#[allow(clippy::too_many_arguments)]
+pub async fn generate(
+    db: &db::Database,
+    openai: &LlmClient,
+    tools: &tools::Registry,
+    estimator: &dyn tokens::Estimator,
     config: &RwLock<Config>,
     channel_id: &ChannelId,
     msg: &SerenityMessage,
@@ -20,85 +28,368 @@ pub async fn generate<'d>(
     mentions_me: bool,
     chat_mode: ChatMode,
 ) -> eyre::Result<()> {
-    let contexts = context::build(transaction, channel_id, config, chat_mode).await?;
-    let should_reply =
-        mentions_me || social::should_reply(contexts.social_context, openai, config).await?;
-    if !should_reply {
+    let overrides = model_overrides(db, msg).await?;
+    let contexts = context::build(
+        db,
+        openai,
+        estimator,
+        Scope::of(msg),
+        config,
+        chat_mode,
+        overrides.model.as_deref(),
+        None,
+    )
+    .await?;
+    let decision = if mentions_me {
+        db::NewDecision {
+            message: msg.id,
+            channel: *channel_id,
+            model: None,
+            should_reply: true,
+            confidence: None,
+            reason: Some("Lumi was mentioned or messaged directly".into()),
+            latency: None,
+        }
+    } else {
+        social::should_reply(
+            contexts.social_context,
+            openai,
+            config,
+            Scope::of(msg),
+            msg.id,
+        )
+        .await?
+    };
+    db.acquire().await?.conn().save_decision(&decision).await?;
+    if !decision.should_reply {
         return Ok(());
     }
-    let typing = channel_id.start_typing(&ctx.http);
-    let response = generate_completion(contexts.chat_context, openai, config).await?;
-    let response = &response.choices.first().unwrap().message;
-    let Some(content) = &response.content else {
-        return Ok(());
-    };
+    let mut chat_context = contexts.chat_context;
+    if let Some(recall) = contexts.recall {
+        recall
+            .apply(&mut chat_context, db, openai, config, Scope::of(msg))
+            .await;
+    }
+    respond(
+        db,
+        chat_context,
+        openai,
+        tools,
+        config,
+        &overrides,
+        msg,
+        ctx,
+        vec![],
+    )
+    .await
+}
 
-    if content.is_empty() {
-        return Ok(());
+/// Regenerates Lumi's reply to an edited message in place, editing the previously sent replies
+#[allow(clippy::too_many_arguments)]
+pub async fn regenerate(
+    db: &db::Database,
+    openai: &LlmClient,
+    tools: &tools::Registry,
+    estimator: &dyn tokens::Estimator,
+    config: &RwLock<Config>,
+    msg: &SerenityMessage,
+    ctx: &Context,
+    chat_mode: ChatMode,
+    previous: Vec<SerenityMessage>,
+) -> eyre::Result<()> {
+    // The previous replies are replaced, so they shouldn't be part of the context
+    let mut transaction = db.begin().await?;
+    for reply in &previous {
+        transaction.conn().delete_message(reply.id).await?;
     }
+    transaction.commit().await?;
+    let overrides = model_overrides(db, msg).await?;
+    let contexts = context::build(
+        db,
+        openai,
+        estimator,
+        Scope::of(msg),
+        config,
+        chat_mode,
+        overrides.model.as_deref(),
+        Some(msg.id),
+    )
+    .await?;
+    let mut chat_context = contexts.chat_context;
+    if let Some(recall) = contexts.recall {
+        recall
+            .apply(&mut chat_context, db, openai, config, Scope::of(msg))
+            .await;
+    }
+    respond(
+        db,
+        chat_context,
+        openai,
+        tools,
+        config,
+        &overrides,
+        msg,
+        ctx,
+        previous,
+    )
+    .await
+}
+
+/// The channel's model and sampling settings
+async fn model_overrides(
+    db: &db::Database,
+    msg: &SerenityMessage,
+) -> eyre::Result<db::ModelOverrides> {
+    let mut conn = db.acquire().await?;
+    let mut overrides = conn
+        .conn()
+        .channel(msg.channel_id)
+        .await?
+        .map(|channel| channel.overrides)
+        .unwrap_or_default();
+    // Channels without a model of their own use their guild's
+    if overrides.model.is_none()
+        && let Some(guild) = msg.guild_id
+    {
+        overrides.model = conn
+            .conn()
+            .guild(guild)
+            .await?
+            .and_then(|guild| guild.model);
+    }
+    Ok(overrides)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn respond(
+    db: &db::Database,
+    context: Vec<ChatCompletionMessage>,
+    openai: &LlmClient,
+    tools: &tools::Registry,
+    config: &RwLock<Config>,
+    overrides: &db::ModelOverrides,
+    msg: &SerenityMessage,
+    ctx: &Context,
+    previous: Vec<SerenityMessage>,
+) -> eyre::Result<()> {
+    let typing = msg.channel_id.start_typing(&ctx.http);
+    let replies = reply(
+        db, context, openai, tools, config, overrides, msg, ctx, previous,
+    )
+    .await?;
+    typing.stop();
+    let mut transaction = db.begin().await?;
+    for reply in replies {
+        transaction
+            .conn()
+            .save_message(&db::NewMessage {
+                id: reply.id,
+                is_self: true,
+                mentions_self: true,
+                sender: reply.author.id,
+                sender_name: reply.author.name.to_owned(),
+                sender_display_name: reply.author.display_name().to_owned(),
+                guild: msg.guild_id,
+                channel: msg.channel_id,
+                contents: reply.content_safe(ctx),
+                reply: reply.referenced_message.as_ref().map(|m| m.id),
+            })
+            .await?;
+    }
+    transaction.commit().await?;
+
+    Ok(())
+}
 
-    let Ok(reply) = msg
-        .channel_id
-        .send_message(
-            &ctx,
-            CreateMessage::new()
-                .reference_message(msg)
-                .content(content)
-                .allowed_mentions(CreateAllowedMentions::new()),
+/// Runs the chat model until it produces a final answer, executing any tool calls it requests
+/// along the way and sending the answer as one or more replies
+#[allow(clippy::too_many_arguments)]
+async fn reply(
+    db: &db::Database,
+    mut context: Vec<ChatCompletionMessage>,
+    openai: &LlmClient,
+    tools: &tools::Registry,
+    config: &RwLock<Config>,
+    overrides: &db::ModelOverrides,
+    msg: &SerenityMessage,
+    ctx: &Context,
+    mut replies: Vec<SerenityMessage>,
+) -> eyre::Result<Vec<SerenityMessage>> {
+    let (chain, streaming, interval, max_iterations, definitions) = {
+        let config = &config.read().await.openrouter;
+        let capabilities = config.chat_capabilities(overrides.model.as_deref());
+        (
+            ModelChain::from(&config.chat).prefer(overrides.model.as_deref()),
+            capabilities.stream,
+            Duration::from_millis(config.stream_interval),
+            config.max_tool_iterations.max(1),
+            capabilities.tools.then(|| tools.definitions()),
         )
-        .await
-    else {
-        return Ok(());
     };
-    typing.stop();
-    sqlx::query(indoc! {"
-        INSERT INTO messages (
-            id, is_self, mentions_self, sender, sender_name, sender_display_name, guild, channel, contents, reply
-        ) VALUES (
-            $2, true, true, $3, $4, $5, $6, $1, $7, $8
+    let tool_context = tools::ToolContext { ctx, msg, db };
+
+    if streaming && replies.is_empty() {
+        replies.push(
+            msg.channel_id
+                .send_message(
+                    &ctx,
+                    CreateMessage::new()
+                        .reference_message(reply_reference(msg))
+                        .content(STREAM_PLACEHOLDER)
+                        .allowed_mentions(CreateAllowedMentions::new()),
+                )
+                .await?,
         );
-    "})
-    .bind(channel_id.get() as i64)
-    .bind(reply.id.get() as i64)
-    .bind(reply.author.id.get() as i64)
-    .bind(&reply.author.name)
-    .bind(reply.author.display_name())
-    .bind(msg.guild_id.map(|id| id.get() as i64))
-    .bind(reply.content_safe(&ctx))
-    .bind(reply.referenced_message.map(|m| m.id.get() as i64))
-    .execute(&mut **transaction)
-    .await?;
+    }
+
+    let mut content = String::new();
+    for iteration in 1..=max_iterations {
+        let mut request = build_request(context.clone(), config, overrides).await;
+        if let Some(definitions) = &definitions {
+            request.tools = Some(definitions.to_owned());
+            // The last iteration has to produce an answer
+            request.tool_choice = Some(if iteration < max_iterations {
+                ToolChoiceType::Auto
+            } else {
+                ToolChoiceType::None
+            });
+        }
+
+        if !content.is_empty() {
+            content.push_str("\n\n");
+        }
+        let prefix_len = content.len();
+        let tool_calls = if streaming {
+            let mut stream = openai
+                .stream_completion(request, &chain, config, Scope::of(msg))
+                .await?;
+            let mut last_edit = Instant::now();
+            while let Some(delta) = stream.next().await? {
+                content.push_str(&delta);
+                if last_edit.elapsed() >= interval && !content.trim().is_empty() {
+                    send_parts(&mut replies, &content, msg, ctx).await?;
+                    last_edit = Instant::now();
+                }
+            }
+            if let Some((model, usage)) = stream.usage() {
+                openai
+                    .record_usage(Scope::of(msg), db::UsageKind::Chat, model, usage)
+                    .await;
+            }
+            stream.into_tool_calls()
+        } else {
+            let response = openai
+                .chat_completion(request, &chain, config, Scope::of(msg), db::UsageKind::Chat)
+                .await?;
+            let Some(choice) = response.choices.into_iter().next() else {
+                eyre::bail!("Chat model returned no choices");
+            };
+            let message = choice.message;
+            content.push_str(&message.content.unwrap_or_default());
+            message.tool_calls.unwrap_or_default()
+        };
 
+        if tool_calls.is_empty() {
+            break;
+        }
+        context.push(ChatCompletionMessage {
+            role: MessageRole::assistant,
+            content: OpenAIContent::Text(content[prefix_len..].to_owned()),
+            name: None,
+            tool_calls: Some(tool_calls.to_owned()),
+            tool_call_id: None,
+        });
+        for call in &tool_calls {
+            context.push(tools.execute(call, &tool_context).await);
+        }
+    }
+
+    if content.trim().is_empty() {
+        // Nothing would replace them, and a placeholder left up looks like Lumi is still thinking
+        for reply in replies {
+            reply.delete(&ctx).await?;
+        }
+        eyre::bail!("The chat model didn't produce an answer");
+    }
+    send_parts(&mut replies, &content, msg, ctx).await?;
+    Ok(replies)
+}
+
+/// Brings the sent replies in line with the content, editing existing parts, sending new parts
+/// as replies to the previous one and deleting parts that are no longer needed
+async fn send_parts(
+    replies: &mut Vec<SerenityMessage>,
+    content: &str,
+    msg: &SerenityMessage,
+    ctx: &Context,
+) -> eyre::Result<()> {
+    let parts = split::split(content, split::MESSAGE_LIMIT);
+    let parts_len = parts.len();
+    for (i, part) in parts.into_iter().enumerate() {
+        if let Some(reply) = replies.get_mut(i) {
+            if reply.content != part {
+                reply.edit(&ctx, EditMessage::new().content(part)).await?;
+            }
+            continue;
+        }
+        let reference = match replies.last() {
+            Some(reply) => MessageReference::from(reply),
+            None => reply_reference(msg),
+        };
+        let reply = msg
+            .channel_id
+            .send_message(
+                &ctx,
+                CreateMessage::new()
+                    .reference_message(reference)
+                    .content(part)
+                    .allowed_mentions(CreateAllowedMentions::new()),
+            )
+            .await?;
+        replies.push(reply);
+    }
+    while replies.len() > parts_len {
+        replies.pop().unwrap().delete(&ctx).await?;
+    }
     Ok(())
 }
 
-async fn generate_completion(
+// Replies in a thread opened from a message can't reference it, the message lives in the parent
+// channel, so those go out as plain messages instead of failing
+fn reply_reference(msg: &SerenityMessage) -> MessageReference {
+    let mut reference = MessageReference::from(msg);
+    reference.fail_if_not_exists = Some(false);
+    reference
+}
+
+async fn build_request(
     context: Vec<ChatCompletionMessage>,
-    openai: &Mutex<OpenAIClient>,
     config: &RwLock<Config>,
-) -> eyre::Result<ChatCompletionResponse> {
-    let body = {
-        let config = &config.read().await.openrouter.chat;
-        ChatCompletionRequest {
-            model: config.model.to_owned(),
-            max_tokens: None,
-            temperature: Some(0.6_f64),
-            top_p: Some(0.99_f64),
-            n: Some(1),
-            stream: Some(false),
-            stop: None,
-            presence_penalty: None,
-            frequency_penalty: None,
-            logit_bias: None,
-            user: None,
-            messages: context,
-            response_format: None,
-            seed: None,
-            tools: None,
-            parallel_tool_calls: None,
-            tool_choice: None,
-            reasoning: config.reasoning.to_owned(),
-        }
-    };
-    Ok(openai.lock().await.chat_completion(body).await?)
+    overrides: &db::ModelOverrides,
+) -> ChatCompletionRequest {
+    let config = &config.read().await.openrouter.chat;
+    ChatCompletionRequest {
+        model: overrides.model.as_ref().unwrap_or(&config.model).to_owned(),
+        max_tokens: overrides.max_tokens.or(config.max_tokens),
+        temperature: Some(overrides.temperature.or(config.temperature).unwrap_or(0.6)),
+        top_p: Some(overrides.top_p.or(config.top_p).unwrap_or(0.99)),
+        n: Some(1),
+        stream: Some(false),
+        stop: None,
+        presence_penalty: None,
+        frequency_penalty: None,
+        logit_bias: None,
+        user: None,
+        messages: context,
+        response_format: None,
+        seed: None,
+        tools: None,
+        parallel_tool_calls: None,
+        tool_choice: None,
+        reasoning: overrides
+            .reasoning
+            .as_ref()
+            .or(config.reasoning.as_ref())
+            .cloned(),
+    }
 }
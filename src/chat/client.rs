@@ -0,0 +1,354 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use openai_api_rs::v1::{
+    api::OpenAIClientBuilder,
+    chat_completion::{ChatCompletionRequest, ChatCompletionResponse},
+    common::Usage,
+    embedding::EmbeddingRequest,
+    error::APIError,
+};
+use serenity::all::{ChannelId, GuildId, Message, UserId};
+use tokio::sync::{OwnedSemaphorePermit, RwLock, Semaphore};
+
+use crate::{
+    Config, ConfigModel, ConfigOpenrouter, OPENROUTER_ENDPOINT,
+    chat::stream::{self, CompletionStream},
+    db,
+};
+
+// However many retries are configured, no single wait is longer than this
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Who a completion is made for, used to limit concurrency per channel and to attribute usage
+#[derive(Clone, Copy)]
+pub struct Scope {
+    pub guild: Option<GuildId>,
+    pub channel: ChannelId,
+    pub user: Option<UserId>,
+}
+
+impl Scope {
+    pub fn of(msg: &Message) -> Self {
+        Self {
+            guild: msg.guild_id,
+            channel: msg.channel_id,
+            user: Some(msg.author.id),
+        }
+    }
+}
+
+/// Sends completion requests concurrently, up to a global and a per-channel limit. Requests past
+/// either limit wait in line in the order they were made
+pub struct LlmClient {
+    global: Arc<Semaphore>,
+    channels: std::sync::Mutex<HashMap<ChannelId, Arc<Semaphore>>>,
+    channel_limit: usize,
+    db: db::Database,
+}
+
+/// A slot for one in-flight request, released when dropped
+pub struct Permit {
+    _channel: OwnedSemaphorePermit,
+    _global: OwnedSemaphorePermit,
+}
+
+impl LlmClient {
+    pub fn new(config: &ConfigOpenrouter, db: db::Database) -> Self {
+        Self {
+            db,
+            global: Arc::new(Semaphore::new(config.max_concurrent_requests.max(1))),
+            channels: Default::default(),
+            channel_limit: config.max_channel_requests.max(1),
+        }
+    }
+
+    pub async fn acquire(&self, channel: ChannelId) -> Permit {
+        let semaphore = {
+            let mut channels = self.channels.lock().unwrap();
+            // Semaphores nobody holds or waits on are only referenced by the map
+            channels.retain(|_, semaphore| Arc::strong_count(semaphore) > 1);
+            channels
+                .entry(channel)
+                .or_insert_with(|| Arc::new(Semaphore::new(self.channel_limit)))
+                .to_owned()
+        };
+        // Waiting on the channel first keeps a busy channel from tying up global slots
+        let channel = semaphore
+            .acquire_owned()
+            .await
+            .expect("Channel semaphore closed");
+        let global = self
+            .global
+            .to_owned()
+            .acquire_owned()
+            .await
+            .expect("Global semaphore closed");
+        Permit {
+            _channel: channel,
+            _global: global,
+        }
+    }
+
+    pub async fn chat_completion(
+        &self,
+        request: ChatCompletionRequest,
+        chain: &ModelChain,
+        config: &RwLock<Config>,
+        scope: Scope,
+        kind: db::UsageKind,
+    ) -> eyre::Result<ChatCompletionResponse> {
+        let api_key = config.read().await.openrouter.api_key.to_owned();
+        let response = self
+            .with_fallbacks(request, chain, |request| {
+                let api_key = api_key.to_owned();
+                async move {
+                    // The client is cheap to build and needs `&mut self`, so every request gets its own
+                    let mut openai = OpenAIClientBuilder::new()
+                        .with_api_key(api_key)
+                        .with_endpoint(OPENROUTER_ENDPOINT)
+                        .build()
+                        .map_err(|err| eyre::eyre!("Failed to build OpenAI client: {err}"))?;
+                    let _permit = self.acquire(scope.channel).await;
+                    Ok(openai.chat_completion(request).await?)
+                }
+            })
+            .await?;
+        self.record_usage(scope, kind, &response.model, &response.usage)
+            .await;
+        Ok(response)
+    }
+
+    /// Embeds each of the texts, normalized to unit length. Embeddings only feed retrieval, so they
+    /// aren't retried
+    pub async fn embed(
+        &self,
+        texts: Vec<String>,
+        config: &RwLock<Config>,
+        scope: Scope,
+    ) -> eyre::Result<Vec<Vec<f32>>> {
+        let (api_key, endpoint, model) = {
+            let config = &config.read().await.openrouter;
+            let Some(embeddings) = &config.embeddings else {
+                eyre::bail!("No embedding model configured");
+            };
+            (
+                embeddings
+                    .api_key
+                    .as_ref()
+                    .unwrap_or(&config.api_key)
+                    .to_owned(),
+                embeddings
+                    .endpoint
+                    .as_deref()
+                    .unwrap_or(OPENROUTER_ENDPOINT)
+                    .to_owned(),
+                embeddings.model.to_owned(),
+            )
+        };
+        let mut openai = OpenAIClientBuilder::new()
+            .with_api_key(api_key)
+            .with_endpoint(endpoint)
+            .build()
+            .map_err(|err| eyre::eyre!("Failed to build OpenAI client: {err}"))?;
+        let response = {
+            let _permit = self.acquire(scope.channel).await;
+            openai
+                .embedding(EmbeddingRequest::new(model, texts))
+                .await?
+        };
+        let usage = Usage {
+            prompt_tokens: response.usage.prompt_tokens,
+            completion_tokens: 0,
+            total_tokens: response.usage.total_tokens,
+        };
+        self.record_usage(scope, db::UsageKind::Embedding, &response.model, &usage)
+            .await;
+
+        let mut data = response.data;
+        data.sort_by_key(|data| data.index);
+        Ok(data
+            .into_iter()
+            .map(|data| {
+                let norm = data.embedding.iter().map(|v| v * v).sum::<f32>().sqrt();
+                data.embedding
+                    .into_iter()
+                    .map(|v| if norm > 0.0 { v / norm } else { v })
+                    .collect()
+            })
+            .collect())
+    }
+
+    /// Opens a streamed completion, only failures before the first chunk fall back
+    pub async fn stream_completion(
+        &self,
+        request: ChatCompletionRequest,
+        chain: &ModelChain,
+        config: &RwLock<Config>,
+        scope: Scope,
+    ) -> eyre::Result<CompletionStream> {
+        self.with_fallbacks(request, chain, |request| async move {
+            let permit = self.acquire(scope.channel).await;
+            stream::stream_completion(request, config, permit).await
+        })
+        .await
+    }
+
+    /// Records the tokens a completion used, failing to do so doesn't fail the completion
+    pub async fn record_usage(
+        &self,
+        scope: Scope,
+        kind: db::UsageKind,
+        model: &str,
+        usage: &Usage,
+    ) {
+        let usage = db::NewUsage {
+            guild: scope.guild,
+            channel: scope.channel,
+            sender: scope.user,
+            kind,
+            model: model.to_owned(),
+            prompt_tokens: usage.prompt_tokens.max(0) as u64,
+            completion_tokens: usage.completion_tokens.max(0) as u64,
+        };
+        let res = match self.db.acquire().await {
+            Ok(mut conn) => conn.conn().record_usage(&usage).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = res {
+            println!("Error recording usage: {err:?}");
+        }
+    }
+
+    /// Tries every model in the chain in order, retrying transient failures with exponential
+    /// backoff and moving on to the next model once retries run out or a failure is permanent
+    async fn with_fallbacks<T, F, Fut>(
+        &self,
+        request: ChatCompletionRequest,
+        chain: &ModelChain,
+        mut send: F,
+    ) -> eyre::Result<T>
+    where
+        F: FnMut(ChatCompletionRequest) -> Fut,
+        Fut: Future<Output = eyre::Result<T>>,
+    {
+        let mut last_err = None;
+        for model in &chain.models {
+            let mut request = request.to_owned();
+            request.model = model.to_owned();
+            for attempt in 0..=chain.retries {
+                if attempt > 0 {
+                    tokio::time::sleep(chain.delay(attempt)).await;
+                }
+                match send(request.to_owned()).await {
+                    Ok(response) => return Ok(response),
+                    Err(err) => {
+                        println!("Completion with {model} failed: {err:?}");
+                        let transient = is_transient(&err);
+                        last_err = Some(err);
+                        if !transient {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| eyre::eyre!("No models configured")))
+    }
+}
+
+/// The models to try for a request in order, and how to retry each of them
+pub struct ModelChain {
+    models: Vec<String>,
+    retries: u32,
+    backoff: Duration,
+}
+
+impl ModelChain {
+    /// How long to wait before a retry, doubling the backoff with every attempt up to a limit
+    fn delay(&self, attempt: u32) -> Duration {
+        attempt
+            .checked_sub(1)
+            .and_then(|exponent| 2_u32.checked_pow(exponent))
+            .and_then(|factor| self.backoff.checked_mul(factor))
+            .unwrap_or(MAX_BACKOFF)
+            .min(MAX_BACKOFF)
+    }
+
+    /// Tries the model first, before the configured ones
+    pub fn prefer(mut self, model: Option<&str>) -> Self {
+        if let Some(model) = model {
+            self.models.retain(|other| other != model);
+            self.models.insert(0, model.to_owned());
+        }
+        self
+    }
+}
+
+impl From<&ConfigModel> for ModelChain {
+    fn from(config: &ConfigModel) -> Self {
+        Self {
+            models: std::iter::once(&config.model)
+                .chain(&config.fallbacks)
+                .cloned()
+                .collect(),
+            retries: config.retries,
+            backoff: Duration::from_millis(config.backoff),
+        }
+    }
+}
+
+// The HTTP status a failed request was answered with, if it got an answer at all
+fn status(err: &eyre::Report) -> Option<u16> {
+    let message = match err.downcast_ref::<APIError>() {
+        Some(APIError::ReqwestError(_)) => return None,
+        Some(APIError::CustomError { message }) => message.to_owned(),
+        None if err.downcast_ref::<reqwest::Error>().is_some() => return None,
+        None => err.to_string(),
+    };
+    message.get(..3).and_then(|status| status.parse().ok())
+}
+
+// Rate limits, timeouts, server errors and responses that failed to arrive or parse are worth
+// retrying, other client errors will fail the same way again
+fn is_transient(err: &eyre::Report) -> bool {
+    match status(err) {
+        Some(status) => status == 408 || status == 429 || status >= 500,
+        None => true,
+    }
+}
+
+/// Whether the provider refused the request itself, such as for a parameter the model doesn't
+/// support, rather than failing to serve it
+pub fn is_rejected(err: &eyre::Report) -> bool {
+    matches!(status(err), Some(400 | 422))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_backoff(backoff: Duration) -> ModelChain {
+        ModelChain {
+            models: vec![],
+            retries: u32::MAX,
+            backoff,
+        }
+    }
+
+    #[test]
+    fn doubles_the_backoff_with_every_attempt() {
+        let chain = with_backoff(Duration::from_secs(1));
+        assert_eq!(chain.delay(1), Duration::from_secs(1));
+        assert_eq!(chain.delay(2), Duration::from_secs(2));
+        assert_eq!(chain.delay(4), Duration::from_secs(8));
+    }
+
+    #[test]
+    fn caps_the_backoff_instead_of_overflowing() {
+        let chain = with_backoff(Duration::from_secs(1));
+        assert_eq!(chain.delay(7), MAX_BACKOFF);
+        assert_eq!(chain.delay(40), MAX_BACKOFF);
+        assert_eq!(chain.delay(u32::MAX), MAX_BACKOFF);
+        assert_eq!(with_backoff(Duration::MAX).delay(2), MAX_BACKOFF);
+    }
+}
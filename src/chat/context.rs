@@ -1,71 +1,307 @@
-use indoc::indoc;
+use std::{
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
 use openai_api_rs::v1::chat_completion::{Content as OpenAIContent, *};
 use serenity::all::*;
-use sqlx::{Postgres, Transaction};
 use tokio::sync::RwLock;
 
-use crate::{Config, chat::social::ShouldReply, db};
+use crate::{
+    Config,
+    chat::{
+        client::{LlmClient, Scope},
+        retrieval,
+        social::ShouldReply,
+        summary, tokens,
+    },
+    db,
+};
+
+// The serialized social verdict that precedes every message in the social context
+const SHOULD_REPLY_TOKENS: usize = 12;
+// Memories are only recalled for the people who spoke most recently
+const MEMORY_SUBJECTS: usize = 10;
+// Discord's signed attachment URLs expire after about a day, and a single image the provider
+// can't fetch fails the whole request, so older images are only described
+const ATTACHMENT_URL_LIFETIME: u64 = 20 * 60 * 60;
+// Recalled messages are only there for reference, long ones are cut short
+const RECALLED_MESSAGE_LENGTH: usize = 500;
 
 pub struct Contexts {
     pub chat_context: Vec<ChatCompletionMessage>,
     pub social_context: Vec<ChatCompletionMessage>,
+    pub recall: Option<Recall>,
+}
+
+/// Older history to recall into the chat context, which costs an embeddings request and so waits
+/// until Lumi decided to reply
+pub struct Recall {
+    latest: db::Message,
+    before: MessageId,
+    all_messages: bool,
+    // Where the recalled messages go in the chat context, after the summary
+    position: usize,
+}
+
+impl Recall {
+    pub async fn apply(
+        self,
+        chat_context: &mut Vec<ChatCompletionMessage>,
+        db: &db::Database,
+        openai: &LlmClient,
+        config: &RwLock<Config>,
+        scope: Scope,
+    ) {
+        let recalled = match retrieval::recall(
+            db,
+            openai,
+            config,
+            scope,
+            &self.latest,
+            self.before,
+            self.all_messages,
+        )
+        .await
+        {
+            Ok(recalled) => recalled,
+            Err(err) => {
+                println!("Error recalling older messages: {err:?}");
+                return;
+            }
+        };
+        if recalled.is_empty() {
+            return;
+        }
+        let mut contents = "Older messages from this channel that may be relevant:".to_owned();
+        for message in recalled {
+            let text = message
+                .contents
+                .chars()
+                .take(RECALLED_MESSAGE_LENGTH)
+                .collect::<String>();
+            contents.push_str(&format!(
+                "\n- {} ({}): {text}",
+                message.sender_display_name, message.sender_name
+            ));
+        }
+        chat_context.insert(
+            self.position,
+            ChatCompletionMessage {
+                role: MessageRole::system,
+                content: OpenAIContent::Text(contents),
+                name: None,
+                tool_calls: None,
+                tool_call_id: None,
+            },
+        );
+    }
+}
+
+struct Entry {
+    message: db::Message,
+    chat: ChatCompletionMessage,
+    social: ChatCompletionMessage,
 }
 
-pub async fn build<'d>(
-    transaction: &mut Transaction<'d, Postgres>,
-    channel_id: &ChannelId,
+#[allow(clippy::too_many_arguments)]
+pub async fn build(
+    db: &db::Database,
+    openai: &LlmClient,
+    estimator: &dyn tokens::Estimator,
+    scope: Scope,
     config: &RwLock<Config>,
     chat_mode: db::ChatMode,
+    model: Option<&str>,
+    until: Option<MessageId>,
 ) -> eyre::Result<Contexts> {
-    let chat_system_prompt: db::SystemPrompt = sqlx::query_as(indoc! {"
-        SELECT sp.*
-        FROM channels ch
-        JOIN system_prompts sp ON sp.id = ch.system_prompt
-        WHERE ch.id = $1;
-    "})
-    .bind(channel_id.get() as i64)
-    .fetch_one(&mut **transaction)
-    .await?;
-
-    let social_system_prompt: db::SystemPrompt = sqlx::query_as(indoc! {"
-        SELECT *
-        FROM system_prompts
-        WHERE id = 1;
-    "})
-    .fetch_one(&mut **transaction)
-    .await?;
-
-    let context: Vec<db::Message> = sqlx::query_as(indoc! {"
-        SELECT m.*,
-            rm.sender_name AS reply_sender_name,
-            rm.contents AS reply_contents
-        FROM messages m
-        JOIN channels c ON c.id = m.channel
-        LEFT JOIN messages rm ON rm.id = m.reply
-        WHERE c.id = $1
-            AND m.time > c.context_window
-            AND (m.mentions_self IS TRUE OR $2 IS TRUE)
-        ORDER BY m.id ASC;
-    "})
-    .bind(channel_id.get() as i64)
-    .bind(chat_mode != db::ChatMode::MentionsOnly)
-    .fetch_all(&mut **transaction)
-    .await?;
-
-    if context.len() >= config.read().await.openrouter.window_threshold {
-        let middle_index = context.len() / 2;
-        if middle_index < context.len() {
-            let middle_message = context.get(middle_index).unwrap();
-            sqlx::query(indoc! {"
-                UPDATE channels
-                SET context_window = $2
-                WHERE id = $1;
-            "})
-            .bind(channel_id.get() as i64)
-            .bind(middle_message.time as i64)
-            .execute(&mut **transaction)
-            .await?;
+    let channel_id = scope.channel;
+    let mut pooled = db.acquire().await?;
+    let mut conn = pooled.conn();
+    let Some(mut chat_system_prompt) = conn.channel_system_prompt(channel_id).await? else {
+        eyre::bail!("Channel has no system prompt");
+    };
+    let social_system_prompt = conn.system_prompt(db::SOCIAL_PROMPT_ID).await?;
+
+    let all_messages = chat_mode != db::ChatMode::MentionsOnly;
+    let channel = conn.channel(channel_id).await?;
+    let parent = channel.as_ref().and_then(|channel| channel.parent);
+    let mut summary = channel.and_then(|channel| channel.summary);
+    let context = conn
+        .context_messages(channel_id, all_messages, until)
+        .await?;
+    // Threads start out knowing the message they were started from
+    let seed = match parent {
+        Some(parent) => {
+            let limit = config.read().await.discord.thread_parent_context;
+            conn.thread_seed(ChannelId::new(parent), channel_id, limit, all_messages)
+                .await?
+        }
+        None => vec![],
+    };
+
+    let mut subjects: Vec<&db::Message> = vec![];
+    for message in seed.iter().chain(&context).rev() {
+        if subjects.len() >= MEMORY_SUBJECTS {
+            break;
+        }
+        if !message.is_self && !subjects.iter().any(|other| other.sender == message.sender) {
+            subjects.push(message);
+        }
+    }
+    let mut memories = String::new();
+    for subject in subjects {
+        for memory in conn
+            .memories(UserId::new(subject.sender), scope.guild)
+            .await?
+        {
+            memories.push_str(&format!(
+                "\n- {} ({}): {}",
+                subject.sender_display_name, subject.sender_name, memory.contents
+            ));
+        }
+    }
+    if !memories.is_empty() {
+        chat_system_prompt.contents.push_str(&format!(
+            "\n\nWhat you remember about the people in this conversation:{memories}"
+        ));
+    }
+
+    let attachments = conn.context_attachments(channel_id).await?;
+    let mut message_attachments: HashMap<u64, Vec<db::Attachment>> = HashMap::new();
+    for attachment in attachments {
+        message_attachments
+            .entry(attachment.message)
+            .or_default()
+            .push(attachment);
+    }
+    // Summarizing can take a while, the connection shouldn't be held through it
+    drop(pooled);
+
+    let (
+        chat_vision,
+        social_vision,
+        window_threshold,
+        summarizes,
+        recalls,
+        chat_budget,
+        social_budget,
+    ) = {
+        let config = &config.read().await.openrouter;
+        let chat = config.chat_capabilities(model);
+        (
+            chat.vision,
+            config.social.capabilities.vision,
+            config.window_threshold,
+            config.summary.is_some(),
+            config.embeddings.is_some(),
+            chat.context_budget(),
+            config.social.capabilities.context_budget(),
+        )
+    };
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let to_entry = |message: db::Message| {
+        let attachments = message_attachments
+            .get(&message.id)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        let fresh = now.saturating_sub(message.time) < ATTACHMENT_URL_LIFETIME;
+        let (role, content) = match message.is_self {
+            true => (
+                MessageRole::assistant,
+                OpenAIContent::Text(message.contents.to_owned()),
+            ),
+            false => (
+                MessageRole::user,
+                build_content(&message, attachments, chat_vision && fresh),
+            ),
+        };
+        let chat = ChatCompletionMessage {
+            role,
+            content,
+            name: None,
+            tool_calls: None,
+            tool_call_id: None,
+        };
+        let social = ChatCompletionMessage {
+            role: MessageRole::user,
+            content: build_content(&message, attachments, social_vision && fresh),
+            name: None,
+            tool_calls: None,
+            tool_call_id: None,
+        };
+        Entry {
+            message,
+            chat,
+            social,
+        }
+    };
+    let seed = seed.into_iter().map(to_entry).collect::<Vec<_>>();
+    let mut entries = context.into_iter().map(to_entry).collect::<Vec<_>>();
+
+    // How many of the oldest messages have to leave the context window
+    let cut = if let Some(budget) = chat_budget {
+        let budget = budget
+            .saturating_sub(estimator.estimate(&chat_system_prompt.contents))
+            .saturating_sub(summary.as_deref().map_or(0, |s| estimator.estimate(s)))
+            .saturating_sub(
+                seed.iter()
+                    .map(|entry| estimator.estimate_message(&entry.chat))
+                    .sum(),
+            );
+        let tokens = entries
+            .iter()
+            .map(|entry| estimator.estimate_message(&entry.chat))
+            .collect::<Vec<_>>();
+        let mut total = tokens.iter().sum::<usize>();
+        let mut cut = 0;
+        if total > budget {
+            // Trimming to half the budget keeps the window from moving on every message
+            while cut + 1 < tokens.len() && total > budget / 2 {
+                total -= tokens[cut];
+                cut += 1;
+            }
+        }
+        cut
+    } else if entries.len() >= window_threshold {
+        entries.len() / 2
+    } else {
+        0
+    };
+
+    if cut > 0 {
+        let window = entries[cut].message.time;
+        // Messages sharing the new window's timestamp stay in the context
+        let discarded = entries
+            .iter()
+            .take_while(|entry| entry.message.time < window)
+            .count();
+        if !summarizes {
+            db.acquire()
+                .await?
+                .conn()
+                .set_context_window(channel_id, window)
+                .await?;
+        } else if discarded > 0 {
+            let messages = entries[..discarded]
+                .iter()
+                .map(|entry| &entry.message)
+                .collect::<Vec<_>>();
+            match summary::summarize(summary.as_deref(), &messages, openai, config, scope).await {
+                Ok(new_summary) => {
+                    db.acquire()
+                        .await?
+                        .conn()
+                        .summarize_context(channel_id, window, &new_summary)
+                        .await?;
+                    summary = Some(new_summary);
+                }
+                // The window stays put so the next message tries again
+                Err(err) => println!("Error summarizing context: {err:?}"),
+            }
         }
+        entries.drain(..discarded);
     }
 
     let mut chat_context = vec![];
@@ -87,46 +323,125 @@ pub async fn build<'d>(
         tool_call_id: None,
     });
 
-    for message in context {
-        let built_message = build_contents(&message);
-        let (role, contents) = match message.is_self {
-            true => (MessageRole::assistant, &message.contents),
-            false => (MessageRole::user, &built_message),
-        };
-
-        chat_context.push(ChatCompletionMessage {
-            role,
-            content: OpenAIContent::Text(contents.to_owned()),
+    if let Some(summary) = summary {
+        let summary = ChatCompletionMessage {
+            role: MessageRole::system,
+            content: OpenAIContent::Text(format!(
+                "Summary of the earlier conversation:\n{summary}"
+            )),
             name: None,
             tool_calls: None,
             tool_call_id: None,
-        });
+        };
+        chat_context.push(summary.clone());
+        social_context.push(summary);
+    }
 
-        let social_serialized = serde_json::to_string(&ShouldReply {
-            should_reply: message.is_self,
+    // Older messages that left the window come back when they relate to the latest one
+    let recall = if recalls
+        && let Some(first) = entries.first()
+        && let Some(latest) = entries.iter().rev().find(|entry| !entry.message.is_self)
+    {
+        Some(Recall {
+            latest: latest.message.clone(),
+            before: MessageId::new(first.message.id),
+            all_messages,
+            position: chat_context.len(),
         })
-        .unwrap();
-        social_context.push(ChatCompletionMessage {
-            role: MessageRole::assistant,
-            content: OpenAIContent::Text(social_serialized),
-            name: None,
-            tool_calls: None,
-            tool_call_id: None,
-        });
+    } else {
+        None
+    };
 
-        social_context.push(ChatCompletionMessage {
-            role: MessageRole::user,
-            content: OpenAIContent::Text(built_message),
-            name: None,
-            tool_calls: None,
-            tool_call_id: None,
-        });
+    // Seeded messages come before the thread's own and never leave the context
+    for entry in seed {
+        chat_context.push(entry.chat);
+        social_context.push(should_reply_message(entry.message.is_self));
+        social_context.push(entry.social);
+    }
+
+    // The social model only needs recent messages, so it is trimmed without moving the window
+    let mut social_start = 0;
+    if let Some(budget) = social_budget {
+        let mut total = social_context
+            .iter()
+            .map(|message| estimator.estimate_message(message))
+            .sum::<usize>();
+        social_start = entries.len();
+        for (i, entry) in entries.iter().enumerate().rev() {
+            total += estimator.estimate_message(&entry.social) + SHOULD_REPLY_TOKENS;
+            if total > budget && i + 1 < entries.len() {
+                break;
+            }
+            social_start = i;
+        }
+    }
+
+    for (i, entry) in entries.into_iter().enumerate() {
+        chat_context.push(entry.chat);
+        if i < social_start {
+            continue;
+        }
+
+        social_context.push(should_reply_message(entry.message.is_self));
+        social_context.push(entry.social);
     }
 
     Ok(Contexts {
         chat_context,
         social_context,
+        recall,
+    })
+}
+
+// The social model's verdict on a past message, Lumi replied exactly when the message is its own
+fn should_reply_message(is_self: bool) -> ChatCompletionMessage {
+    let social_serialized = serde_json::to_string(&ShouldReply {
+        should_reply: is_self,
+        confidence: None,
+        reason: None,
     })
+    .unwrap();
+    ChatCompletionMessage {
+        role: MessageRole::assistant,
+        content: OpenAIContent::Text(social_serialized),
+        name: None,
+        tool_calls: None,
+        tool_call_id: None,
+    }
+}
+
+fn build_content(
+    message: &db::Message,
+    attachments: &[db::Attachment],
+    vision: bool,
+) -> OpenAIContent {
+    let mut text = build_contents(message);
+    let mut images = vec![];
+    for attachment in attachments {
+        text.push('\n');
+        text.push_str(&attachment.placeholder());
+        if vision && attachment.is_image() {
+            images.push(ImageUrl {
+                r#type: ContentType::image_url,
+                text: None,
+                image_url: Some(ImageUrlType {
+                    url: attachment.url.to_owned(),
+                }),
+            });
+        }
+    }
+    if images.is_empty() {
+        return OpenAIContent::Text(text);
+    }
+    images.insert(
+        0,
+        ImageUrl {
+            r#type: ContentType::text,
+            text: Some(text),
+            image_url: None,
+        },
+    );
+    OpenAIContent::ImageUrl(images)
 }
 
 fn build_contents(message: &db::Message) -> String {
@@ -0,0 +1,116 @@
+use std::{collections::HashMap, time::Duration};
+
+use serenity::all::{ChannelId, MessageId};
+
+struct Burst {
+    latest: MessageId,
+    mentions_me: bool,
+}
+
+/// Coalesces bursts of messages per channel so a reply is only considered once the channel
+/// goes quiet
+#[derive(Default)]
+pub struct Debouncer {
+    bursts: std::sync::Mutex<HashMap<ChannelId, Burst>>,
+}
+
+impl Debouncer {
+    /// Waits out the interval, returning whether any message in the burst mentioned Lumi if no
+    /// later message arrived in the meantime, or `None` if a later message takes over the burst
+    pub async fn settle(
+        &self,
+        channel: ChannelId,
+        message: MessageId,
+        mentions_me: bool,
+        interval: Duration,
+    ) -> Option<bool> {
+        {
+            let mut bursts = self.bursts.lock().unwrap();
+            let burst = bursts.entry(channel).or_insert(Burst {
+                latest: message,
+                mentions_me: false,
+            });
+            burst.latest = burst.latest.max(message);
+            burst.mentions_me |= mentions_me;
+        }
+        tokio::time::sleep(interval).await;
+
+        let mut bursts = self.bursts.lock().unwrap();
+        if bursts.get(&channel)?.latest != message {
+            return None;
+        }
+        bursts.remove(&channel).map(|burst| burst.mentions_me)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::{Instant, sleep};
+
+    use super::*;
+
+    const INTERVAL: Duration = Duration::from_secs(5);
+    const CHANNEL: ChannelId = ChannelId::new(1);
+
+    #[tokio::test(start_paused = true)]
+    async fn coalesces_a_burst_into_its_latest_message() {
+        let debouncer = Debouncer::default();
+        let start = Instant::now();
+        let settled = tokio::join!(
+            debouncer.settle(CHANNEL, MessageId::new(1), true, INTERVAL),
+            async {
+                sleep(Duration::from_secs(1)).await;
+                debouncer
+                    .settle(CHANNEL, MessageId::new(2), false, INTERVAL)
+                    .await
+            },
+            async {
+                sleep(Duration::from_secs(2)).await;
+                debouncer
+                    .settle(CHANNEL, MessageId::new(3), false, INTERVAL)
+                    .await
+            },
+        );
+        // Only the last message answers, and it remembers the mention from earlier in the burst
+        assert_eq!(settled, (None, None, Some(true)));
+        assert_eq!(start.elapsed(), Duration::from_secs(2) + INTERVAL);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn newer_message_supersedes_an_earlier_waiter() {
+        let debouncer = Debouncer::default();
+        let settled = tokio::join!(
+            debouncer.settle(CHANNEL, MessageId::new(1), false, INTERVAL),
+            async {
+                sleep(INTERVAL - Duration::from_millis(1)).await;
+                debouncer
+                    .settle(CHANNEL, MessageId::new(2), false, INTERVAL)
+                    .await
+            },
+        );
+        assert_eq!(settled, (None, Some(false)));
+
+        // Once settled the burst is over, so the next message starts a new one
+        assert_eq!(
+            debouncer
+                .settle(CHANNEL, MessageId::new(3), false, INTERVAL)
+                .await,
+            Some(false)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn channels_settle_independently() {
+        let debouncer = Debouncer::default();
+        let settled = tokio::join!(
+            debouncer.settle(CHANNEL, MessageId::new(1), true, INTERVAL),
+            async {
+                sleep(Duration::from_secs(1)).await;
+                debouncer
+                    .settle(ChannelId::new(2), MessageId::new(2), false, INTERVAL)
+                    .await
+            },
+        );
+        assert_eq!(settled, (Some(true), Some(false)));
+    }
+}
@@ -0,0 +1,73 @@
+use std::{collections::HashMap, collections::VecDeque, hash::Hash, time::Duration};
+
+use tokio::time::Instant;
+
+/// Allows a number of events per key within a sliding window
+pub struct RateLimiter<K> {
+    events: std::sync::Mutex<HashMap<K, VecDeque<Instant>>>,
+}
+
+impl<K> Default for RateLimiter<K> {
+    fn default() -> Self {
+        Self {
+            events: Default::default(),
+        }
+    }
+}
+
+impl<K: Hash + Eq> RateLimiter<K> {
+    /// Records an event for the key, or returns how long until the next one is allowed if the key
+    /// already used up its limit
+    pub fn hit(&self, key: K, limit: u32, window: Duration) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut events = self.events.lock().unwrap();
+        events.retain(|_, times| {
+            while times.front().is_some_and(|time| now - *time >= window) {
+                times.pop_front();
+            }
+            !times.is_empty()
+        });
+        let times = events.entry(key).or_default();
+        if times.len() >= limit as usize {
+            // A limit of zero allows nothing, so there's no event to wait out
+            return Err(times.front().map_or(window, |time| window - (now - *time)));
+        }
+        times.push_back(now);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_secs(60);
+
+    #[tokio::test(start_paused = true)]
+    async fn allows_up_to_the_limit_within_the_window() {
+        let limiter = RateLimiter::default();
+        assert_eq!(limiter.hit(1, 2, WINDOW), Ok(()));
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(limiter.hit(1, 2, WINDOW), Ok(()));
+        assert_eq!(limiter.hit(1, 2, WINDOW), Err(Duration::from_secs(50)));
+        // Other keys have limits of their own
+        assert_eq!(limiter.hit(2, 2, WINDOW), Ok(()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn allows_again_once_the_oldest_event_leaves_the_window() {
+        let limiter = RateLimiter::default();
+        assert_eq!(limiter.hit(1, 1, WINDOW), Ok(()));
+        tokio::time::advance(Duration::from_secs(59)).await;
+        assert_eq!(limiter.hit(1, 1, WINDOW), Err(Duration::from_secs(1)));
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(limiter.hit(1, 1, WINDOW), Ok(()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn zero_limit_denies_everything() {
+        let limiter = RateLimiter::default();
+        assert_eq!(limiter.hit(1, 0, WINDOW), Err(WINDOW));
+        assert_eq!(limiter.hit(1, 0, WINDOW), Err(WINDOW));
+    }
+}
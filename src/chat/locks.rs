@@ -0,0 +1,24 @@
+use std::{collections::HashMap, sync::Arc};
+
+use serenity::all::ChannelId;
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+/// Serializes replies within a channel while other channels carry on independently
+#[derive(Default)]
+pub struct ChannelLocks {
+    locks: std::sync::Mutex<HashMap<ChannelId, Arc<Mutex<()>>>>,
+}
+
+impl ChannelLocks {
+    /// Waits for the channel's previous reply to finish, holding the channel until the guard is
+    /// dropped
+    pub async fn lock(&self, channel: ChannelId) -> OwnedMutexGuard<()> {
+        let lock = {
+            let mut locks = self.locks.lock().unwrap();
+            // Locks nobody holds or waits on are only referenced by the map
+            locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+            locks.entry(channel).or_default().to_owned()
+        };
+        lock.lock_owned().await
+    }
+}
@@ -1,3 +1,13 @@
 pub mod chatbot;
+pub mod client;
 pub mod context;
+pub mod debounce;
+pub mod limits;
+pub mod locks;
+pub mod retrieval;
 pub mod social;
+pub mod split;
+pub mod stream;
+pub mod summary;
+pub mod tokens;
+pub mod tools;
@@ -0,0 +1,83 @@
+use serenity::all::{ChannelId, MessageId};
+use tokio::sync::RwLock;
+
+use crate::{
+    Config,
+    chat::client::{LlmClient, Scope},
+    db,
+};
+
+// How many older messages without an embedding are embedded along with each query, catching up on
+// history a little at a time
+const EMBEDDING_BATCH: usize = 32;
+
+/// The channel's messages before `before` that are most similar to the latest message, embedding
+/// the latest message and some of the messages missing an embedding along the way
+pub async fn recall(
+    db: &db::Database,
+    openai: &LlmClient,
+    config: &RwLock<Config>,
+    scope: Scope,
+    latest: &db::Message,
+    before: MessageId,
+    all_messages: bool,
+) -> eyre::Result<Vec<db::Message>> {
+    let (model, recall, min_similarity) = {
+        let config = &config.read().await.openrouter;
+        let Some(config) = &config.embeddings else {
+            eyre::bail!("No embedding model configured");
+        };
+        (
+            config.model.to_owned(),
+            config.recall,
+            config.min_similarity,
+        )
+    };
+    let missing = db
+        .acquire()
+        .await?
+        .conn()
+        .missing_embeddings(scope.channel, before, &model, all_messages, EMBEDDING_BATCH)
+        .await?;
+    let texts = std::iter::once(latest)
+        .chain(&missing)
+        .map(|message| {
+            format!(
+                "{} ({}): {}",
+                message.sender_display_name, message.sender_name, message.contents
+            )
+        })
+        .collect();
+    let embeddings = openai.embed(texts, config, scope).await?;
+    let Some(query) = embeddings.first() else {
+        eyre::bail!("Embedding model returned no embeddings");
+    };
+
+    let mut transaction = db.begin().await?;
+    for (message, embedding) in std::iter::once(latest).chain(&missing).zip(&embeddings) {
+        transaction
+            .conn()
+            .save_embedding(
+                MessageId::new(message.id),
+                ChannelId::new(message.channel),
+                &model,
+                embedding,
+            )
+            .await?;
+    }
+    transaction.commit().await?;
+
+    db.acquire()
+        .await?
+        .conn()
+        .similar_messages(
+            scope.channel,
+            before,
+            &model,
+            query,
+            all_messages,
+            recall,
+            min_similarity,
+        )
+        .await
+}
@@ -1,32 +1,76 @@
-use openai_api_rs::v1::{
-    api::OpenAIClient,
-    chat_completion::{
-        ChatCompletionMessage, ChatCompletionRequest, ChatCompletionResponse, Content, MessageRole,
-    },
+use openai_api_rs::v1::chat_completion::{
+    ChatCompletionMessage, ChatCompletionRequest, ChatCompletionResponse, Content, MessageRole,
 };
 use serde::{Deserialize, Serialize};
-use tokio::sync::{Mutex, RwLock};
+use serde_json::json;
+use serenity::all::MessageId;
+use tokio::{sync::RwLock, time::Instant};
 
-use crate::Config;
+use crate::{
+    Config,
+    chat::client::{self, LlmClient, ModelChain, Scope},
+    db,
+};
 
 #[derive(Serialize, Deserialize)]
 pub struct ShouldReply {
     pub should_reply: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub confidence: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
 }
 
+/// Asks the social model whether Lumi should reply to the latest message
 pub async fn should_reply(
     mut context: Vec<ChatCompletionMessage>,
-    openai: &Mutex<OpenAIClient>,
+    openai: &LlmClient,
     config: &RwLock<Config>,
-) -> eyre::Result<bool> {
+    scope: Scope,
+    message: MessageId,
+) -> eyre::Result<db::NewDecision> {
+    let start = Instant::now();
     let mut i = config.read().await.openrouter.max_attempts;
+    let mut structured = true;
     loop {
-        let response = generate_completion(context.clone(), openai, config).await?;
-        let response = &response.choices.first().unwrap().message;
+        let response =
+            match generate_completion(context.clone(), openai, config, scope, structured).await {
+                Ok(response) => response,
+                // Not every provider supports JSON schemas, the parse retries below cover those
+                Err(err) if structured && client::is_rejected(&err) => {
+                    println!("Structured social completion failed, retrying without: {err:?}");
+                    structured = false;
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+        let model = response.model;
+        let Some(choice) = response.choices.first() else {
+            eyre::bail!("Social model returned no choices");
+        };
+        let response = &choice.message;
         let result =
             serde_json::from_str::<ShouldReply>(&response.content.clone().unwrap_or_default());
         match result {
-            Ok(result) => return Ok(result.should_reply),
+            Ok(result) => {
+                println!(
+                    "Social verdict in {}: should_reply={}, confidence={:?}, reason={:?}",
+                    scope.channel, result.should_reply, result.confidence, result.reason
+                );
+                let threshold = config.read().await.openrouter.reply_threshold;
+                return Ok(db::NewDecision {
+                    message,
+                    channel: scope.channel,
+                    model: Some(model),
+                    should_reply: result.should_reply
+                        && result
+                            .confidence
+                            .is_none_or(|confidence| confidence >= threshold),
+                    confidence: result.confidence,
+                    reason: result.reason,
+                    latency: Some(start.elapsed()),
+                });
+            }
             Err(err) => {
                 i -= 1;
                 if i <= 0 {
@@ -46,16 +90,18 @@ pub async fn should_reply(
 
 async fn generate_completion(
     context: Vec<ChatCompletionMessage>,
-    openai: &Mutex<OpenAIClient>,
+    openai: &LlmClient,
     config: &RwLock<Config>,
+    scope: Scope,
+    structured: bool,
 ) -> eyre::Result<ChatCompletionResponse> {
-    let body = {
+    let (body, chain) = {
         let config = &config.read().await.openrouter.social;
-        ChatCompletionRequest {
+        let body = ChatCompletionRequest {
             model: config.model.to_owned(),
-            max_tokens: None,
-            temperature: Some(0.0),
-            top_p: None,
+            max_tokens: config.max_tokens,
+            temperature: Some(config.temperature.unwrap_or(0.0)),
+            top_p: config.top_p,
             n: Some(1),
             stream: Some(false),
             stop: None,
@@ -64,13 +110,45 @@ async fn generate_completion(
             logit_bias: None,
             user: None,
             messages: context,
-            response_format: None,
+            response_format: structured.then(response_format),
             seed: None,
             tools: None,
             parallel_tool_calls: None,
             tool_choice: None,
             reasoning: config.reasoning.to_owned(),
-        }
+        };
+        (body, ModelChain::from(config))
     };
-    Ok(openai.lock().await.chat_completion(body).await?)
+    openai
+        .chat_completion(body, &chain, config, scope, db::UsageKind::Social)
+        .await
+}
+
+fn response_format() -> serde_json::Value {
+    json!({
+        "type": "json_schema",
+        "json_schema": {
+            "name": "should_reply",
+            "strict": true,
+            "schema": {
+                "type": "object",
+                "properties": {
+                    "should_reply": {
+                        "type": "boolean",
+                        "description": "Whether the chatbot should reply to the latest message",
+                    },
+                    "confidence": {
+                        "type": "number",
+                        "description": "How confident the decision is, from 0 to 1",
+                    },
+                    "reason": {
+                        "type": "string",
+                        "description": "A short explanation of the decision",
+                    },
+                },
+                "required": ["should_reply", "confidence", "reason"],
+                "additionalProperties": false,
+            },
+        },
+    })
 }
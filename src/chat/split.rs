@@ -0,0 +1,205 @@
+pub const MESSAGE_LIMIT: usize = 2000;
+
+// Room left on every line-split part for re-opening and closing a code fence
+const FENCE_RESERVE: usize = 64;
+
+/// Splits content into parts of at most `limit` characters, preferring paragraph boundaries and
+/// re-opening code fences that span multiple parts
+pub fn split(content: &str, limit: usize) -> Vec<String> {
+    let mut parts = vec![];
+    let mut current = String::new();
+    for block in blocks(content) {
+        let block_len = block.chars().count();
+        if current.is_empty() && block_len <= limit {
+            current = block;
+            continue;
+        }
+        if !current.is_empty() && current.chars().count() + 2 + block_len <= limit {
+            current.push_str("\n\n");
+            current.push_str(&block);
+            continue;
+        }
+        if !current.is_empty() {
+            parts.push(std::mem::take(&mut current));
+        }
+        if block_len <= limit {
+            current = block;
+        } else {
+            let mut pieces = split_block(&block, limit);
+            current = pieces.pop().unwrap_or_default();
+            parts.extend(pieces);
+        }
+    }
+    if !current.trim().is_empty() {
+        parts.push(current);
+    }
+    parts
+}
+
+fn is_fence(line: &str) -> bool {
+    line.trim_start().starts_with("```")
+}
+
+// Paragraphs separated by blank lines, keeping fenced code blocks whole
+fn blocks(content: &str) -> Vec<String> {
+    let mut blocks = vec![];
+    let mut current: Vec<&str> = vec![];
+    let mut in_fence = false;
+    for line in content.lines() {
+        if !in_fence && line.trim().is_empty() {
+            if !current.is_empty() {
+                blocks.push(current.join("\n"));
+                current.clear();
+            }
+            continue;
+        }
+        if is_fence(line) {
+            in_fence = !in_fence;
+        }
+        current.push(line);
+    }
+    if !current.is_empty() {
+        blocks.push(current.join("\n"));
+    }
+    blocks
+}
+
+// Splits a single oversized block on line boundaries, closing and re-opening code fences
+fn split_block(block: &str, limit: usize) -> Vec<String> {
+    let mut pieces = vec![];
+    let mut current = String::new();
+    let mut fence: Option<String> = None;
+    for line in block.lines() {
+        for segment in split_line(line, limit.saturating_sub(FENCE_RESERVE).max(1)) {
+            let closing = if fence.is_some() { 4 } else { 0 };
+            if !current.is_empty()
+                && current.chars().count() + 1 + segment.chars().count() + closing > limit
+            {
+                if fence.is_some() {
+                    current.push_str("\n```");
+                }
+                pieces.push(std::mem::take(&mut current));
+                if let Some(fence) = &fence {
+                    current = fence.to_owned();
+                }
+            }
+            if !current.is_empty() {
+                current.push('\n');
+            }
+            current.push_str(&segment);
+        }
+        if is_fence(line) {
+            fence = match fence {
+                Some(_) => None,
+                None => Some(line.trim().chars().take(FENCE_RESERVE / 2).collect()),
+            };
+        }
+    }
+    if !current.is_empty() {
+        pieces.push(current);
+    }
+    pieces
+}
+
+// Hard wraps a single line, breaking at whitespace where possible
+fn split_line(line: &str, width: usize) -> Vec<String> {
+    let mut segments = vec![];
+    let mut rest = line.chars().collect::<Vec<_>>();
+    while rest.len() > width {
+        let cut = rest[..width]
+            .iter()
+            .rposition(|c| c.is_whitespace())
+            .filter(|i| *i > 0)
+            .unwrap_or(width);
+        segments.push(rest[..cut].iter().collect());
+        let skip = if cut < width { 1 } else { 0 };
+        rest = rest[cut + skip..].to_vec();
+    }
+    segments.push(rest.into_iter().collect());
+    segments
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_within(parts: &[String], limit: usize) {
+        for part in parts {
+            assert!(part.chars().count() <= limit, "{part:?} is over {limit}");
+        }
+    }
+
+    #[test]
+    fn keeps_short_content_whole() {
+        assert_eq!(split("Hello\n\nWorld", 2000), vec!["Hello\n\nWorld"]);
+    }
+
+    #[test]
+    fn drops_empty_content() {
+        assert!(split("", 2000).is_empty());
+        assert!(split(" \n\n \n", 2000).is_empty());
+    }
+
+    #[test]
+    fn packs_paragraphs_up_to_the_limit() {
+        let parts = split("aaaa\n\nbbbb\n\ncccc", 10);
+        assert_eq!(parts, vec!["aaaa\n\nbbbb", "cccc"]);
+    }
+
+    #[test]
+    fn wraps_long_lines_at_whitespace() {
+        let content = "word ".repeat(100);
+        let parts = split(content.trim(), 100);
+        assert_within(&parts, 100);
+        assert!(parts.iter().all(|part| !part.starts_with(' ')));
+        assert_eq!(parts.join(" ").split_whitespace().count(), 100);
+    }
+
+    #[test]
+    fn wraps_lines_without_whitespace() {
+        let parts = split(&"x".repeat(250), 100);
+        assert_within(&parts, 100);
+        assert_eq!(parts.concat().replace('\n', ""), "x".repeat(250));
+    }
+
+    #[test]
+    fn counts_characters_rather_than_bytes() {
+        let parts = split(&"é".repeat(150), 100);
+        assert_within(&parts, 100);
+        assert_eq!(parts.concat().replace('\n', ""), "é".repeat(150));
+    }
+
+    #[test]
+    fn reopens_code_fences_across_parts() {
+        let lines = (0..40)
+            .map(|i| format!("let value_{i} = {i};"))
+            .collect::<Vec<_>>();
+        let content = format!("Here you go:\n\n```rust\n{}\n```\n\nDone", lines.join("\n"));
+        let parts = split(&content, 200);
+        assert_within(&parts, 200);
+        let code = parts
+            .iter()
+            .filter(|part| part.contains("let value_"))
+            .collect::<Vec<_>>();
+        assert!(code.len() > 1);
+        for part in code {
+            assert!(
+                part.starts_with("```rust\n"),
+                "{part:?} doesn't open the fence"
+            );
+            assert_eq!(
+                part.matches("```").count(),
+                2,
+                "{part:?} doesn't close the fence"
+            );
+        }
+        assert_eq!(parts.first().unwrap(), "Here you go:");
+        assert!(parts.last().unwrap().ends_with("```\n\nDone"));
+    }
+
+    #[test]
+    fn keeps_blank_lines_inside_code_fences() {
+        let content = "```\na\n\nb\n```";
+        assert_eq!(split(content, 2000), vec![content]);
+    }
+}
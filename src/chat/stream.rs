@@ -0,0 +1,175 @@
+use eyre::bail;
+use openai_api_rs::v1::{
+    chat_completion::{ChatCompletionRequest, ToolCall, ToolCallFunction},
+    common::Usage,
+};
+use reqwest::header::CONTENT_TYPE;
+use serde::Deserialize;
+use tokio::sync::RwLock;
+
+use crate::{Config, OPENROUTER_ENDPOINT, chat::client::Permit};
+
+#[derive(Deserialize)]
+struct StreamChunk {
+    #[serde(default)]
+    choices: Vec<StreamChoice>,
+    error: Option<serde_json::Value>,
+    model: Option<String>,
+    usage: Option<Usage>,
+}
+
+#[derive(Deserialize)]
+struct StreamChoice {
+    delta: StreamDelta,
+}
+
+#[derive(Deserialize)]
+struct StreamDelta {
+    content: Option<String>,
+    tool_calls: Option<Vec<ToolCallDelta>>,
+}
+
+#[derive(Deserialize)]
+struct ToolCallDelta {
+    index: usize,
+    id: Option<String>,
+    function: Option<ToolCallFunctionDelta>,
+}
+
+#[derive(Deserialize)]
+struct ToolCallFunctionDelta {
+    name: Option<String>,
+    arguments: Option<String>,
+}
+
+/// Server-sent events from a streamed chat completion, yielding content deltas as they arrive
+pub struct CompletionStream {
+    response: reqwest::Response,
+    buffer: Vec<u8>,
+    done: bool,
+    tool_calls: Vec<ToolCall>,
+    model: String,
+    usage: Option<Usage>,
+    _permit: Permit,
+}
+
+pub async fn stream_completion(
+    mut request: ChatCompletionRequest,
+    config: &RwLock<Config>,
+    permit: Permit,
+) -> eyre::Result<CompletionStream> {
+    request.stream = Some(true);
+    let api_key = config.read().await.openrouter.api_key.to_owned();
+    let response = reqwest::Client::new()
+        .post(format!("{OPENROUTER_ENDPOINT}chat/completions"))
+        .bearer_auth(api_key)
+        .header(CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(&request)?)
+        .send()
+        .await?;
+    let status = response.status();
+    if !status.is_success() {
+        bail!("{status}: {}", response.text().await.unwrap_or_default());
+    }
+    Ok(CompletionStream {
+        response,
+        buffer: vec![],
+        done: false,
+        tool_calls: vec![],
+        model: request.model,
+        usage: None,
+        _permit: permit,
+    })
+}
+
+impl CompletionStream {
+    /// Returns the next content delta, or `None` once the completion has finished
+    pub async fn next(&mut self) -> eyre::Result<Option<String>> {
+        loop {
+            if self.done {
+                return Ok(None);
+            }
+            while let Some(end) = self.buffer.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = self.buffer.drain(..=end).collect();
+                let line = String::from_utf8_lossy(&line);
+                let line = line.trim();
+                // Blank lines separate events and lines starting with ':' are keep-alive comments
+                let Some(data) = line.strip_prefix("data:") else {
+                    continue;
+                };
+                let data = data.trim();
+                if data == "[DONE]" {
+                    self.done = true;
+                    return Ok(None);
+                }
+                let chunk: StreamChunk = serde_json::from_str(data)?;
+                if let Some(error) = chunk.error {
+                    bail!("Error in completion stream: {error}");
+                }
+                if let Some(model) = chunk.model {
+                    self.model = model;
+                }
+                // Usage arrives with the last chunk, which usually has no choices
+                if let Some(usage) = chunk.usage {
+                    self.usage = Some(usage);
+                }
+                let Some(choice) = chunk.choices.into_iter().next() else {
+                    continue;
+                };
+                for delta in choice.delta.tool_calls.unwrap_or_default() {
+                    self.push_tool_call(delta);
+                }
+                if let Some(content) = choice.delta.content.filter(|content| !content.is_empty()) {
+                    return Ok(Some(content));
+                }
+            }
+            match self.response.chunk().await? {
+                Some(bytes) => self.buffer.extend_from_slice(&bytes),
+                None => self.done = true,
+            }
+        }
+    }
+
+    /// The model that answered and the tokens it used, known once `next` has returned `None`
+    pub fn usage(&self) -> Option<(&str, &Usage)> {
+        self.usage
+            .as_ref()
+            .map(|usage| (self.model.as_str(), usage))
+    }
+
+    /// Tool calls requested by the model, complete once `next` has returned `None`
+    pub fn into_tool_calls(self) -> Vec<ToolCall> {
+        self.tool_calls
+    }
+
+    // Tool calls arrive in fragments, the first carrying the id and name and the rest appending to
+    // the arguments
+    fn push_tool_call(&mut self, delta: ToolCallDelta) {
+        while self.tool_calls.len() <= delta.index {
+            self.tool_calls.push(ToolCall {
+                id: String::new(),
+                r#type: "function".to_owned(),
+                function: ToolCallFunction {
+                    name: None,
+                    arguments: None,
+                },
+            });
+        }
+        let call = &mut self.tool_calls[delta.index];
+        if let Some(id) = delta.id {
+            call.id = id;
+        }
+        let Some(function) = delta.function else {
+            return;
+        };
+        if let Some(name) = function.name {
+            call.function.name.get_or_insert_default().push_str(&name);
+        }
+        if let Some(arguments) = function.arguments {
+            call.function
+                .arguments
+                .get_or_insert_default()
+                .push_str(&arguments);
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use openai_api_rs::v1::chat_completion::{
+    ChatCompletionMessage, ChatCompletionRequest, Content, MessageRole,
+};
+use tokio::sync::RwLock;
+
+use crate::{
+    Config,
+    chat::client::{LlmClient, ModelChain, Scope},
+    db,
+};
+
+const SUMMARY_PROMPT: &str = "You maintain a running summary of a Discord conversation so it can \
+    be continued once the older messages are gone. Given the previous summary and the messages \
+    that followed it, write a new summary that keeps the participants, topics, decisions, open \
+    questions and anything people asked to be remembered. Be concise, write in plain prose and \
+    reply with the summary only.";
+
+/// Condenses the messages leaving the context window into the channel's rolling summary
+pub async fn summarize(
+    previous: Option<&str>,
+    messages: &[&db::Message],
+    openai: &LlmClient,
+    config: &RwLock<Config>,
+    scope: Scope,
+) -> eyre::Result<String> {
+    let mut transcript = String::new();
+    if let Some(previous) = previous {
+        transcript.push_str(&format!("Previous summary:\n{previous}\n\n"));
+    }
+    transcript.push_str("Messages:\n");
+    for message in messages {
+        transcript.push_str(&format!(
+            "{} ({}): {}\n",
+            message.sender_display_name, message.sender_name, message.contents
+        ));
+    }
+
+    let (request, chain) = {
+        let config = &config.read().await.openrouter;
+        let Some(config) = &config.summary else {
+            eyre::bail!("No summary model configured");
+        };
+        let request = ChatCompletionRequest {
+            model: config.model.to_owned(),
+            max_tokens: config.max_tokens,
+            temperature: Some(config.temperature.unwrap_or(0.2)),
+            top_p: config.top_p,
+            n: Some(1),
+            stream: Some(false),
+            stop: None,
+            presence_penalty: None,
+            frequency_penalty: None,
+            logit_bias: None,
+            user: None,
+            messages: vec![
+                ChatCompletionMessage {
+                    role: MessageRole::system,
+                    content: Content::Text(SUMMARY_PROMPT.into()),
+                    name: None,
+                    tool_calls: None,
+                    tool_call_id: None,
+                },
+                ChatCompletionMessage {
+                    role: MessageRole::user,
+                    content: Content::Text(transcript),
+                    name: None,
+                    tool_calls: None,
+                    tool_call_id: None,
+                },
+            ],
+            response_format: None,
+            seed: None,
+            tools: None,
+            parallel_tool_calls: None,
+            tool_choice: None,
+            reasoning: config.reasoning.to_owned(),
+        };
+        (request, ModelChain::from(config))
+    };
+    let response = openai
+        .chat_completion(request, &chain, config, scope, db::UsageKind::Summary)
+        .await?;
+    let Some(summary) = response
+        .choices
+        .into_iter()
+        .next()
+        .and_then(|choice| choice.message.content)
+        .filter(|summary| !summary.trim().is_empty())
+    else {
+        eyre::bail!("Summary model returned no content");
+    };
+    Ok(summary.trim().to_owned())
+}
@@ -0,0 +1,34 @@
+use openai_api_rs::v1::chat_completion::{ChatCompletionMessage, Content};
+
+// Role markers and other formatting every message costs on top of its content
+const MESSAGE_OVERHEAD: usize = 4;
+// What a typical image costs once a vision model has scaled and tiled it
+const IMAGE_TOKENS: usize = 1024;
+
+/// Estimates how many tokens text takes up for a model, implement this to plug in a real tokenizer
+pub trait Estimator: Send + Sync {
+    fn estimate(&self, text: &str) -> usize;
+
+    fn estimate_message(&self, message: &ChatCompletionMessage) -> usize {
+        let content = match &message.content {
+            Content::Text(text) => self.estimate(text),
+            Content::ImageUrl(parts) => parts
+                .iter()
+                .map(|part| match &part.text {
+                    Some(text) => self.estimate(text),
+                    None => IMAGE_TOKENS,
+                })
+                .sum(),
+        };
+        content + MESSAGE_OVERHEAD
+    }
+}
+
+/// Counts roughly four characters per token, which holds up for English text across tokenizers
+pub struct Heuristic;
+
+impl Estimator for Heuristic {
+    fn estimate(&self, text: &str) -> usize {
+        text.chars().count().div_ceil(4)
+    }
+}
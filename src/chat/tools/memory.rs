@@ -0,0 +1,67 @@
+use std::collections::HashMap;
+
+use eyre::bail;
+use openai_api_rs::v1::types::{FunctionParameters, JSONSchemaDefine, JSONSchemaType};
+use serenity::async_trait;
+
+use crate::{
+    chat::tools::{Tool, ToolContext},
+    db,
+};
+
+pub struct Remember;
+
+#[async_trait]
+impl Tool for Remember {
+    fn name(&self) -> &'static str {
+        "remember"
+    }
+
+    fn description(&self) -> &'static str {
+        "Remember a lasting fact about the user you are replying to, such as a preference or \
+        something they shared about themselves, so it can be recalled in later conversations. \
+        Only save what they would want remembered"
+    }
+
+    fn parameters(&self) -> FunctionParameters {
+        FunctionParameters {
+            schema_type: JSONSchemaType::Object,
+            properties: Some(HashMap::from([(
+                "fact".to_owned(),
+                Box::new(JSONSchemaDefine {
+                    schema_type: Some(JSONSchemaType::String),
+                    description: Some("The fact, written as a short sentence".to_owned()),
+                    enum_values: None,
+                    properties: None,
+                    required: None,
+                    items: None,
+                }),
+            )])),
+            required: Some(vec!["fact".to_owned()]),
+        }
+    }
+
+    async fn call(
+        &self,
+        arguments: serde_json::Value,
+        context: &ToolContext<'_>,
+    ) -> eyre::Result<String> {
+        let Some(fact) = arguments["fact"].as_str().map(str::trim) else {
+            bail!("Missing `fact`");
+        };
+        if fact.is_empty() || fact.chars().count() > db::MAX_MEMORY_LENGTH {
+            bail!(
+                "The fact has to be between 1 and {} characters",
+                db::MAX_MEMORY_LENGTH
+            );
+        }
+        context
+            .db
+            .acquire()
+            .await?
+            .conn()
+            .remember(context.msg.author.id, context.msg.guild_id, fact)
+            .await?;
+        Ok(format!("Remembered about {}", context.msg.author.name))
+    }
+}
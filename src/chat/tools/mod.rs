@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+
+use openai_api_rs::v1::{
+    chat_completion::{
+        ChatCompletionMessage, Content, MessageRole, Tool as OpenAITool, ToolCall, ToolType,
+    },
+    types::{Function, FunctionParameters},
+};
+use serenity::{
+    all::{Context, Message as SerenityMessage},
+    async_trait,
+};
+
+use crate::db;
+
+pub mod memory;
+pub mod time;
+
+/// What a tool is allowed to know about the message that triggered it
+pub struct ToolContext<'a> {
+    pub ctx: &'a Context,
+    pub msg: &'a SerenityMessage,
+    pub db: &'a db::Database,
+}
+
+#[async_trait]
+pub trait Tool: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn description(&self) -> &'static str;
+    fn parameters(&self) -> FunctionParameters;
+    async fn call(
+        &self,
+        arguments: serde_json::Value,
+        context: &ToolContext<'_>,
+    ) -> eyre::Result<String>;
+}
+
+pub struct Registry {
+    tools: HashMap<&'static str, Box<dyn Tool>>,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        let mut registry = Self::new();
+        registry.register(time::CurrentTime);
+        registry.register(memory::Remember);
+        registry
+    }
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self {
+            tools: HashMap::new(),
+        }
+    }
+
+    pub fn register(&mut self, tool: impl Tool + 'static) {
+        self.tools.insert(tool.name(), Box::new(tool));
+    }
+
+    pub fn definitions(&self) -> Vec<OpenAITool> {
+        let mut definitions = self
+            .tools
+            .values()
+            .map(|tool| OpenAITool {
+                r#type: ToolType::Function,
+                function: Function {
+                    name: tool.name().to_owned(),
+                    description: Some(tool.description().to_owned()),
+                    parameters: tool.parameters(),
+                },
+            })
+            .collect::<Vec<_>>();
+        definitions.sort_by(|a, b| a.function.name.cmp(&b.function.name));
+        definitions
+    }
+
+    /// Runs a tool call requested by the model, errors are reported back to the model rather than
+    /// aborting the reply so it has a chance to recover
+    pub async fn execute(
+        &self,
+        call: &ToolCall,
+        context: &ToolContext<'_>,
+    ) -> ChatCompletionMessage {
+        let name = call.function.name.to_owned().unwrap_or_default();
+        let result = match self.tools.get(name.as_str()) {
+            Some(tool) => {
+                let arguments = call.function.arguments.as_deref().unwrap_or_default();
+                let arguments = if arguments.trim().is_empty() {
+                    Ok(serde_json::Value::Object(Default::default()))
+                } else {
+                    serde_json::from_str(arguments)
+                };
+                match arguments {
+                    Ok(arguments) => tool.call(arguments, context).await,
+                    Err(err) => Err(err.into()),
+                }
+            }
+            None => Err(eyre::eyre!("Unknown tool `{name}`")),
+        };
+        let content = match result {
+            Ok(content) => content,
+            Err(err) => format!("Error: {err}"),
+        };
+        ChatCompletionMessage {
+            role: MessageRole::tool,
+            content: Content::Text(content),
+            name: Some(name),
+            tool_calls: None,
+            tool_call_id: Some(call.id.to_owned()),
+        }
+    }
+}
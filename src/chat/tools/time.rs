@@ -0,0 +1,35 @@
+use std::collections::HashMap;
+
+use openai_api_rs::v1::types::{FunctionParameters, JSONSchemaType};
+use serenity::{all::Timestamp, async_trait};
+
+use crate::chat::tools::{Tool, ToolContext};
+
+pub struct CurrentTime;
+
+#[async_trait]
+impl Tool for CurrentTime {
+    fn name(&self) -> &'static str {
+        "current_time"
+    }
+
+    fn description(&self) -> &'static str {
+        "Get the current date and time in UTC"
+    }
+
+    fn parameters(&self) -> FunctionParameters {
+        FunctionParameters {
+            schema_type: JSONSchemaType::Object,
+            properties: Some(HashMap::new()),
+            required: None,
+        }
+    }
+
+    async fn call(
+        &self,
+        _arguments: serde_json::Value,
+        _context: &ToolContext<'_>,
+    ) -> eyre::Result<String> {
+        Ok(Timestamp::now().to_string())
+    }
+}
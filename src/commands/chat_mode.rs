@@ -1,6 +1,6 @@
 use std::str::FromStr;
 
-use indoc::indoc;
+use eyre::bail;
 use serenity::all::*;
 
 use crate::{db, handler::Handler};
@@ -10,40 +10,46 @@ pub async fn run(
     command: &CommandInteraction,
     handler: &Handler,
 ) -> eyre::Result<()> {
+    let mut conn = handler.db.acquire().await?;
+    conn.conn()
+        .ensure_channel(command.channel_id, command.guild_id)
+        .await?;
     let response = if let Some(ResolvedOption {
         value: ResolvedValue::String(mode),
         ..
     }) = command.data.options().first().as_ref()
     {
-        let new_mode = db::ChatMode::from_str(&mode)?;
-        sqlx::query(indoc! {"
-            INSERT INTO channels (id, chat_mode)
-            VALUES ($1, $2)
-            ON CONFLICT (id)
-            DO UPDATE SET
-                chat_mode = $2;
-        "})
-        .bind(command.channel_id.get() as i64)
-        .bind(&new_mode)
-        .execute(&handler.db)
-        .await?;
-        format!("Updated Lumi's chat mode to *{}*", new_mode.to_string())
+        let new_mode = match *mode {
+            "inherit" => None,
+            mode => Some(db::ChatMode::from_str(mode)?),
+        };
+        conn.conn()
+            .set_chat_mode(command.channel_id, new_mode.as_ref())
+            .await?;
+        if let Some(new_mode) = new_mode {
+            format!("Updated Lumi's chat mode to *{new_mode}*")
+        } else {
+            let Some(channel) = conn.conn().channel(command.channel_id).await? else {
+                bail!("Channel was not stored");
+            };
+            format!(
+                "Lumi now follows the chat mode from {}, currently *{}*",
+                channel.inherited_from(),
+                channel.chat_mode
+            )
+        }
     } else {
-        let channel: Option<db::Channel> = sqlx::query_as(indoc! {"
-            SELECT *
-            FROM channels
-            WHERE id = $1;
-        "})
-        .bind(command.channel_id.get() as i64)
-        .fetch_optional(&handler.db)
-        .await?;
-        if let Some(channel) = channel {
+        let Some(channel) = conn.conn().channel(command.channel_id).await? else {
+            bail!("Channel was not stored");
+        };
+        if channel.inherits_chat_mode {
             format!(
-                "Lumi's current chat mode is *{}*",
-                channel.chat_mode.to_string()
+                "Lumi's current chat mode is *{}*, from {}",
+                channel.chat_mode,
+                channel.inherited_from()
             )
         } else {
-            "Lumi does not have a chat mode set for this channel".into()
+            format!("Lumi's current chat mode is *{}*", channel.chat_mode)
         }
     };
 
@@ -67,6 +73,7 @@ pub async fn run(
 pub fn register() -> CreateCommand {
     CreateCommand::new("chat_mode")
         .description("Set or view Lumi's chat mode for the current channel")
+        .default_member_permissions(Permissions::MANAGE_CHANNELS)
         .add_option(
             CreateCommandOption::new(
                 CommandOptionType::String,
@@ -75,6 +82,8 @@ pub fn register() -> CreateCommand {
             )
             .add_string_choice("Free Response", "free_response")
             .add_string_choice("Mentions Only", "mentions_only")
-            .add_string_choice("Mentions Only All Context", "mentions_only_all_context"),
+            .add_string_choice("Mentions Only All Context", "mentions_only_all_context")
+            .add_string_choice("Continue In Thread", "continue_in_thread")
+            .add_string_choice("Inherit", "inherit"),
         )
 }
@@ -0,0 +1,111 @@
+use eyre::bail;
+use serenity::all::*;
+
+use crate::handler::Handler;
+
+pub async fn run(
+    ctx: &Context,
+    command: &CommandInteraction,
+    handler: &Handler,
+) -> eyre::Result<()> {
+    let options = command.data.options();
+    let Some(ResolvedOption {
+        name: subcommand,
+        value: ResolvedValue::SubCommand(options),
+        ..
+    }) = options.first()
+    else {
+        bail!("Missing subcommand");
+    };
+
+    let mut conn = handler.db.acquire().await?;
+    let response = match *subcommand {
+        "view" => {
+            let memories = conn
+                .conn()
+                .memories(command.user.id, command.guild_id)
+                .await?;
+            if memories.is_empty() {
+                "Lumi doesn't remember anything about you here".into()
+            } else {
+                let mut response = String::new();
+                for memory in memories {
+                    let line = format!("- `{}` {}\n", memory.id, memory.contents);
+                    if response.len() + line.len() > 4000 {
+                        response.push_str("- ...");
+                        break;
+                    }
+                    response.push_str(&line);
+                }
+                response
+            }
+        }
+        "forget" => {
+            let mut id = None;
+            let mut all = false;
+            for option in options {
+                match (option.name, &option.value) {
+                    ("id", ResolvedValue::Integer(value)) => id = Some(*value),
+                    ("all", ResolvedValue::Boolean(value)) => all = *value,
+                    _ => bail!("Invalid subcommand options"),
+                }
+            }
+            if id.is_none() && !all {
+                bail!("Pick a memory to forget, or forget all of them");
+            }
+            let forgotten = conn
+                .conn()
+                .forget(command.user.id, command.guild_id, id)
+                .await?;
+            match forgotten {
+                0 => "There was nothing to forget".into(),
+                1 => "Lumi forgot 1 memory".into(),
+                n => format!("Lumi forgot {n} memories"),
+            }
+        }
+        _ => bail!("Invalid subcommand"),
+    };
+
+    let response: CreateInteractionResponse = CreateInteractionResponse::Message(
+        CreateInteractionResponseMessage::new()
+            .embed(
+                CreateEmbed::new()
+                    .title("Memory")
+                    .description(response)
+                    .color(2326507),
+            )
+            .ephemeral(true),
+    );
+    if let Err(err) = command.create_response(&ctx, response).await {
+        println!("Error responding to slash command: {err:?}");
+    }
+
+    Ok(())
+}
+
+pub fn register() -> CreateCommand {
+    CreateCommand::new("memory")
+        .description("See or forget what Lumi remembers about you")
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::SubCommand,
+            "view",
+            "List what Lumi remembers about you here",
+        ))
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "forget",
+                "Make Lumi forget something it remembers about you",
+            )
+            .add_sub_option(CreateCommandOption::new(
+                CommandOptionType::Integer,
+                "id",
+                "The memory to forget, as listed by /memory view",
+            ))
+            .add_sub_option(CreateCommandOption::new(
+                CommandOptionType::Boolean,
+                "all",
+                "Forget everything Lumi remembers about you here",
+            )),
+        )
+}
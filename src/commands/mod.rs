@@ -1,4 +1,11 @@
 pub mod chat_mode;
+pub mod memory;
+pub mod model;
+pub mod permissions;
 pub mod reload;
+pub mod remember;
 pub mod reset_context;
+pub mod server_settings;
 pub mod system_prompt;
+pub mod usage;
+pub mod why;
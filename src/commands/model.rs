@@ -0,0 +1,227 @@
+use eyre::bail;
+use openai_api_rs::v1::chat_completion::{Reasoning, ReasoningEffort, ReasoningMode};
+use serenity::all::*;
+
+use crate::{db, handler::Handler};
+
+pub async fn run(
+    ctx: &Context,
+    command: &CommandInteraction,
+    handler: &Handler,
+) -> eyre::Result<()> {
+    let mut conn = handler.db.acquire().await?;
+    let mut overrides = conn
+        .conn()
+        .channel(command.channel_id)
+        .await?
+        .map(|channel| channel.overrides)
+        .unwrap_or_default();
+    let guild_model = match command.guild_id {
+        Some(guild) => conn
+            .conn()
+            .guild(guild)
+            .await?
+            .and_then(|guild| guild.model),
+        None => None,
+    };
+
+    let options = command.data.options();
+    let config = handler.config.read().await;
+    let chat = &config.openrouter.chat;
+    if options.iter().any(|option| {
+        matches!(
+            option,
+            ResolvedOption {
+                name: "reset",
+                value: ResolvedValue::Boolean(true),
+                ..
+            }
+        )
+    }) {
+        overrides = db::ModelOverrides::default();
+    }
+    for option in &options {
+        match (option.name, &option.value) {
+            ("model", ResolvedValue::String(model)) => {
+                if *model == guild_model.as_deref().unwrap_or(&chat.model) {
+                    overrides.model = None;
+                } else if config.openrouter.allows_model(model) {
+                    overrides.model = Some(model.to_string());
+                } else {
+                    bail!("That model isn't in the list of allowed models");
+                }
+            }
+            ("temperature", ResolvedValue::Number(temperature)) => {
+                overrides.temperature = Some(*temperature)
+            }
+            ("top_p", ResolvedValue::Number(top_p)) => overrides.top_p = Some(*top_p),
+            ("max_tokens", ResolvedValue::Integer(max_tokens)) => {
+                overrides.max_tokens = Some(*max_tokens)
+            }
+            ("reasoning", ResolvedValue::String(reasoning)) => {
+                overrides.reasoning = reasoning_choice(reasoning)?
+            }
+            ("reset", ResolvedValue::Boolean(_)) => {}
+            _ => bail!("Invalid command options"),
+        }
+    }
+    let changed = !options.is_empty();
+    if changed {
+        conn.conn()
+            .ensure_channel(command.channel_id, command.guild_id)
+            .await?;
+        conn.conn()
+            .set_model_overrides(command.channel_id, &overrides)
+            .await?;
+    }
+
+    let setting = |value: Option<String>, default: Option<String>| match (value, default) {
+        (Some(value), _) => format!("`{value}`"),
+        (None, Some(default)) => format!("`{default}` (default)"),
+        (None, None) => "provider default".to_owned(),
+    };
+    let reasoning = |reasoning: &Option<Reasoning>| {
+        reasoning
+            .as_ref()
+            .and_then(|reasoning| serde_json::to_string(reasoning).ok())
+    };
+    let response = format!(
+        "{}\n- Model: {}\n- Temperature: {}\n- Top P: {}\n- Max tokens: {}\n- Reasoning: {}",
+        if changed {
+            "Updated Lumi's model settings for this channel"
+        } else {
+            "Lumi's model settings for this channel"
+        },
+        setting(overrides.model, guild_model.or(Some(chat.model.to_owned()))),
+        setting(
+            overrides.temperature.map(|v| v.to_string()),
+            Some(chat.temperature.unwrap_or(0.6).to_string())
+        ),
+        setting(
+            overrides.top_p.map(|v| v.to_string()),
+            Some(chat.top_p.unwrap_or(0.99).to_string())
+        ),
+        setting(
+            overrides.max_tokens.map(|v| v.to_string()),
+            chat.max_tokens.map(|v| v.to_string())
+        ),
+        setting(reasoning(&overrides.reasoning), reasoning(&chat.reasoning)),
+    );
+
+    let response: CreateInteractionResponse = CreateInteractionResponse::Message(
+        CreateInteractionResponseMessage::new()
+            .embed(
+                CreateEmbed::new()
+                    .title("Model")
+                    .description(response)
+                    .color(2326507),
+            )
+            .ephemeral(false),
+    );
+    if let Err(err) = command.create_response(&ctx, response).await {
+        println!("Error responding to slash command: {err:?}");
+    }
+
+    Ok(())
+}
+
+/// Suggests allowed models matching what has been typed so far
+pub async fn autocomplete(
+    ctx: &Context,
+    interaction: &CommandInteraction,
+    handler: &Handler,
+) -> eyre::Result<()> {
+    let typed = interaction
+        .data
+        .autocomplete()
+        .map(|option| option.value.to_lowercase())
+        .unwrap_or_default();
+    let config = handler.config.read().await;
+    let mut response = CreateAutocompleteResponse::new();
+    for model in std::iter::once(&config.openrouter.chat.model)
+        .chain(config.openrouter.models.keys())
+        .filter(|model| model.to_lowercase().contains(&typed))
+        .take(25)
+    {
+        response = response.add_string_choice(model, model);
+    }
+    interaction
+        .create_response(&ctx, CreateInteractionResponse::Autocomplete(response))
+        .await?;
+    Ok(())
+}
+
+pub fn register() -> CreateCommand {
+    CreateCommand::new("model")
+        .description("Set or view the model settings Lumi uses in the current channel")
+        .default_member_permissions(Permissions::MANAGE_CHANNELS)
+        .add_option(
+            CreateCommandOption::new(CommandOptionType::String, "model", "The model to chat with")
+                .set_autocomplete(true),
+        )
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::Number,
+                "temperature",
+                "Sampling temperature, higher is more random",
+            )
+            .min_number_value(0.0)
+            .max_number_value(2.0),
+        )
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::Number,
+                "top_p",
+                "Only sample from the most likely tokens making up this probability",
+            )
+            .min_number_value(0.0)
+            .max_number_value(1.0),
+        )
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::Integer,
+                "max_tokens",
+                "The most tokens a single completion can use",
+            )
+            .min_int_value(1),
+        )
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::String,
+                "reasoning",
+                "How much the model reasons before answering",
+            )
+            .add_string_choice("Default", "default")
+            .add_string_choice("Off", "off")
+            .add_string_choice("Low", "low")
+            .add_string_choice("Medium", "medium")
+            .add_string_choice("High", "high"),
+        )
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::Boolean,
+            "reset",
+            "Go back to the default settings before applying the other options",
+        ))
+}
+
+fn reasoning_choice(choice: &str) -> eyre::Result<Option<Reasoning>> {
+    let effort = match choice {
+        "default" => return Ok(None),
+        "off" => {
+            return Ok(Some(Reasoning {
+                mode: None,
+                exclude: None,
+                enabled: Some(false),
+            }));
+        }
+        "low" => ReasoningEffort::Low,
+        "medium" => ReasoningEffort::Medium,
+        "high" => ReasoningEffort::High,
+        _ => bail!("Invalid reasoning choice"),
+    };
+    Ok(Some(Reasoning {
+        mode: Some(ReasoningMode::Effort { effort }),
+        exclude: None,
+        enabled: Some(true),
+    }))
+}
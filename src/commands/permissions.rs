@@ -0,0 +1,198 @@
+use eyre::bail;
+use serenity::all::*;
+
+use crate::handler::Handler;
+
+/// Whether the user behind a command may run it. Owners can run anything, `reload` and changes to
+/// the system prompts every guild shares need an owner, `permissions` and `server_settings` need
+/// Manage Server, `remember` and `memory` only concern the user themselves, and the other commands
+/// are limited to the guild's allowed roles once any are set
+pub async fn authorize(command: &CommandInteraction, handler: &Handler) -> eyre::Result<bool> {
+    if handler
+        .config
+        .read()
+        .await
+        .discord
+        .owners
+        .contains(&command.user.id.get())
+    {
+        return Ok(true);
+    }
+    match command.data.name.as_str() {
+        "reload" => return Ok(false),
+        "system_prompt" if changes_system_prompts(command) => return Ok(false),
+        "remember" | "memory" => return Ok(true),
+        _ => {}
+    }
+    // Direct messages only concern the user themselves
+    let (Some(guild), Some(member)) = (command.guild_id, command.member.as_ref()) else {
+        return Ok(true);
+    };
+    let manages_guild = member
+        .permissions
+        .is_some_and(|permissions| permissions.administrator() || permissions.manage_guild());
+    if manages_guild
+        || matches!(
+            command.data.name.as_str(),
+            "permissions" | "server_settings"
+        )
+    {
+        return Ok(manages_guild);
+    }
+
+    let roles = handler
+        .db
+        .acquire()
+        .await?
+        .conn()
+        .command_roles(guild)
+        .await?;
+    Ok(roles.is_empty() || member.roles.iter().any(|role| roles.contains(role)))
+}
+
+/// Whether the author of the "erase memory" GIF may reset the channel's context, the same people
+/// who can run `reset_context`: owners, members with Manage Messages or Manage Server, and the
+/// guild's allowed roles once any are set
+pub async fn authorize_reset(
+    ctx: &Context,
+    msg: &Message,
+    handler: &Handler,
+) -> eyre::Result<bool> {
+    if handler
+        .config
+        .read()
+        .await
+        .discord
+        .owners
+        .contains(&msg.author.id.get())
+    {
+        return Ok(true);
+    }
+    let Some(guild) = msg.guild_id else {
+        return Ok(true);
+    };
+    // Without the guild cached the author is treated as having no permissions at all
+    let permissions = msg.author_permissions(ctx).unwrap_or_default();
+    if permissions.administrator() || permissions.manage_guild() || permissions.manage_messages() {
+        return Ok(true);
+    }
+
+    let roles = handler
+        .db
+        .acquire()
+        .await?
+        .conn()
+        .command_roles(guild)
+        .await?;
+    Ok(!roles.is_empty()
+        && msg
+            .member
+            .as_ref()
+            .is_some_and(|member| member.roles.iter().any(|role| roles.contains(role))))
+}
+
+// System prompts aren't scoped to a guild, so creating, editing or deleting one affects them all
+fn changes_system_prompts(command: &CommandInteraction) -> bool {
+    command
+        .data
+        .options
+        .first()
+        .is_some_and(|option| matches!(option.name.as_str(), "create" | "edit" | "delete"))
+}
+
+pub async fn run(
+    ctx: &Context,
+    command: &CommandInteraction,
+    handler: &Handler,
+) -> eyre::Result<()> {
+    let Some(guild) = command.guild_id else {
+        bail!("Permissions can only be managed in a server");
+    };
+    let options = command.data.options();
+    let Some(ResolvedOption {
+        name: subcommand,
+        value: ResolvedValue::SubCommand(options),
+        ..
+    }) = options.first()
+    else {
+        bail!("Missing subcommand");
+    };
+    let role = options.iter().find_map(|option| match option {
+        ResolvedOption {
+            name: "role",
+            value: ResolvedValue::Role(role),
+            ..
+        } => Some(role.id),
+        _ => None,
+    });
+
+    let mut conn = handler.db.acquire().await?;
+    let response = match (*subcommand, role) {
+        ("allow", Some(role)) => {
+            conn.conn().allow_command_role(guild, role).await?;
+            format!("Members with <@&{role}> can now configure Lumi")
+        }
+        ("deny", Some(role)) => {
+            conn.conn().deny_command_role(guild, role).await?;
+            format!("Members with <@&{role}> can no longer configure Lumi")
+        }
+        ("list", _) => {
+            let roles = conn.conn().command_roles(guild).await?;
+            if roles.is_empty() {
+                "No roles are set, anyone Discord lets use Lumi's commands can configure Lumi"
+                    .into()
+            } else {
+                roles.iter().map(|role| format!("- <@&{role}>\n")).collect()
+            }
+        }
+        _ => bail!("Invalid subcommand options"),
+    };
+
+    let response: CreateInteractionResponse = CreateInteractionResponse::Message(
+        CreateInteractionResponseMessage::new()
+            .embed(
+                CreateEmbed::new()
+                    .title("Permissions")
+                    .description(response)
+                    .color(2326507),
+            )
+            .allowed_mentions(CreateAllowedMentions::new())
+            .ephemeral(true),
+    );
+    if let Err(err) = command.create_response(&ctx, response).await {
+        println!("Error responding to slash command: {err:?}");
+    }
+
+    Ok(())
+}
+
+pub fn register() -> CreateCommand {
+    let role = |description: &str| {
+        CreateCommandOption::new(CommandOptionType::Role, "role", description).required(true)
+    };
+    CreateCommand::new("permissions")
+        .description("Manage which roles can configure Lumi in this server")
+        .default_member_permissions(Permissions::MANAGE_GUILD)
+        .dm_permission(false)
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "allow",
+                "Allow a role to configure Lumi",
+            )
+            .add_sub_option(role("The role to allow")),
+        )
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "deny",
+                "Stop allowing a role to configure Lumi",
+            )
+            .add_sub_option(role("The role to remove")),
+        )
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::SubCommand,
+            "list",
+            "List the roles allowed to configure Lumi",
+        ))
+}
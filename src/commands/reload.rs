@@ -33,5 +33,7 @@ pub async fn run(
 }
 
 pub fn register() -> CreateCommand {
-    CreateCommand::new("reload").description("Reload Lumi's config file")
+    CreateCommand::new("reload")
+        .description("Reload Lumi's config file")
+        .default_member_permissions(Permissions::ADMINISTRATOR)
 }
@@ -0,0 +1,60 @@
+use eyre::bail;
+use serenity::all::*;
+
+use crate::{db, handler::Handler};
+
+pub async fn run(
+    ctx: &Context,
+    command: &CommandInteraction,
+    handler: &Handler,
+) -> eyre::Result<()> {
+    let options = command.data.options();
+    let Some(ResolvedOption {
+        value: ResolvedValue::String(fact),
+        ..
+    }) = options.first()
+    else {
+        bail!("Missing fact");
+    };
+    let fact = fact.trim();
+    if fact.is_empty() {
+        bail!("There's nothing to remember");
+    }
+    handler
+        .db
+        .acquire()
+        .await?
+        .conn()
+        .remember(command.user.id, command.guild_id, fact)
+        .await?;
+
+    let response: CreateInteractionResponse = CreateInteractionResponse::Message(
+        CreateInteractionResponseMessage::new()
+            .embed(
+                CreateEmbed::new()
+                    .title("Remembered")
+                    .description(format!("Lumi will remember: {fact}"))
+                    .color(2326507),
+            )
+            .ephemeral(true),
+    );
+    if let Err(err) = command.create_response(&ctx, response).await {
+        println!("Error responding to slash command: {err:?}");
+    }
+
+    Ok(())
+}
+
+pub fn register() -> CreateCommand {
+    CreateCommand::new("remember")
+        .description("Tell Lumi something to remember about you")
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::String,
+                "fact",
+                "What Lumi should remember",
+            )
+            .max_length(db::MAX_MEMORY_LENGTH as u16)
+            .required(true),
+        )
+}
@@ -1,8 +1,6 @@
-use indoc::indoc;
 use serenity::all::*;
-use sqlx::PgPool;
 
-use crate::handler::Handler;
+use crate::{db, handler::Handler};
 
 pub async fn run(
     ctx: &Context,
@@ -28,17 +26,13 @@ pub async fn run(
 }
 
 pub fn register() -> CreateCommand {
-    CreateCommand::new("reset_context").description("Reset Lumi's context for the current channel")
+    CreateCommand::new("reset_context")
+        .description("Reset Lumi's context for the current channel")
+        .default_member_permissions(Permissions::MANAGE_MESSAGES)
 }
 
-pub async fn reset_context(channel_id: &ChannelId, db: &PgPool) -> eyre::Result<()> {
-    sqlx::query(indoc! {"
-        UPDATE channels
-        SET context_window = extract(epoch FROM now())::bigint
-        WHERE id = $1;
-    "})
-    .bind(channel_id.get() as i64)
-    .execute(db)
-    .await?;
-    Ok(())
+pub async fn reset_context(channel_id: &ChannelId, db: &db::Database) -> eyre::Result<()> {
+    let mut transaction = db.begin().await?;
+    transaction.conn().reset_context(*channel_id).await?;
+    transaction.commit().await
 }
@@ -0,0 +1,213 @@
+use std::str::FromStr;
+
+use eyre::bail;
+use serenity::all::*;
+
+use crate::{db, handler::Handler};
+
+pub async fn run(
+    ctx: &Context,
+    command: &CommandInteraction,
+    handler: &Handler,
+) -> eyre::Result<()> {
+    let Some(guild_id) = command.guild_id else {
+        bail!("Server settings can only be managed in a server");
+    };
+    let mut conn = handler.db.acquire().await?;
+    let options = command.data.options();
+    let reset = options.iter().any(|option| {
+        matches!(
+            option,
+            ResolvedOption {
+                name: "reset",
+                value: ResolvedValue::Boolean(true),
+                ..
+            }
+        )
+    });
+    let mut guild = match conn.conn().guild(guild_id).await? {
+        Some(guild) if !reset => guild,
+        _ => db::Guild {
+            id: guild_id.get(),
+            chat_mode: None,
+            system_prompt: None,
+            model: None,
+            user_messages_per_minute: None,
+            channel_completions_per_hour: None,
+            daily_tokens: None,
+            daily_cost: None,
+        },
+    };
+
+    let config = handler.config.read().await;
+    for option in &options {
+        match (option.name, &option.value) {
+            ("chat_mode", ResolvedValue::String(mode)) => {
+                guild.chat_mode = Some(db::ChatMode::from_str(mode)?)
+            }
+            ("system_prompt", ResolvedValue::String(name)) => {
+                let Some(prompt) = conn.conn().system_prompt_by_name(name).await? else {
+                    bail!("No system prompt with that name exists");
+                };
+                if prompt.id == db::SOCIAL_PROMPT_ID {
+                    bail!("The social prompt is reserved for deciding when to reply");
+                }
+                guild.system_prompt = Some(prompt.id);
+            }
+            ("model", ResolvedValue::String(model)) => {
+                if *model == config.openrouter.chat.model {
+                    guild.model = None;
+                } else if config.openrouter.allows_model(model) {
+                    guild.model = Some(model.to_string());
+                } else {
+                    bail!("That model isn't in the list of allowed models");
+                }
+            }
+            ("user_messages_per_minute", ResolvedValue::Integer(limit)) => {
+                guild.user_messages_per_minute = Some(*limit as _)
+            }
+            ("channel_completions_per_hour", ResolvedValue::Integer(limit)) => {
+                guild.channel_completions_per_hour = Some(*limit as _)
+            }
+            ("daily_tokens", ResolvedValue::Integer(limit)) => {
+                guild.daily_tokens = Some(*limit as _)
+            }
+            ("daily_cost", ResolvedValue::Number(limit)) => guild.daily_cost = Some(*limit),
+            ("reset", ResolvedValue::Boolean(_)) => {}
+            _ => bail!("Invalid command options"),
+        }
+    }
+    let changed = !options.is_empty();
+    if changed {
+        conn.conn().set_guild(&guild).await?;
+    }
+
+    let system_prompt = match guild.system_prompt {
+        Some(id) => Some(conn.conn().system_prompt(id).await?.name),
+        None => None,
+    };
+    let setting = |value: Option<String>, default: String| match value {
+        Some(value) => format!("`{value}`"),
+        None => format!("{default} (default)"),
+    };
+    let limit = |value: Option<String>, config: Option<String>| match (value, config) {
+        (Some(value), Some(config)) => format!("`{value}`, capped at `{config}`"),
+        (Some(value), None) => format!("`{value}`"),
+        (None, Some(config)) => format!("`{config}` (default)"),
+        (None, None) => "none".to_owned(),
+    };
+    let limits = &config.limits;
+    let response = format!(
+        "{}\n\
+        - Chat mode: {}\n\
+        - System prompt: {}\n\
+        - Model: {}\n\
+        - Messages per user per minute: {}\n\
+        - Completions per channel per hour: {}\n\
+        - Daily tokens: {}\n\
+        - Daily cost: {}\n\
+        -# Channels that picked their own chat mode or system prompt keep it until they are set to inherit again",
+        if changed {
+            "Updated Lumi's defaults for this server"
+        } else {
+            "Lumi's defaults for this server"
+        },
+        setting(
+            guild.chat_mode.map(|mode| mode.to_string()),
+            format!("*{}*", db::ChatMode::MentionsOnlyAllContext)
+        ),
+        setting(system_prompt, "*default*".into()),
+        setting(guild.model, format!("`{}`", config.openrouter.chat.model)),
+        limit(
+            guild.user_messages_per_minute.map(|v| v.to_string()),
+            limits.user_messages_per_minute.map(|v| v.to_string())
+        ),
+        limit(
+            guild.channel_completions_per_hour.map(|v| v.to_string()),
+            limits.channel_completions_per_hour.map(|v| v.to_string())
+        ),
+        limit(
+            guild.daily_tokens.map(|v| v.to_string()),
+            limits.guild_daily_tokens.map(|v| v.to_string())
+        ),
+        limit(
+            guild.daily_cost.map(|v| format!("${v:.2}")),
+            limits.guild_daily_cost.map(|v| format!("${v:.2}"))
+        ),
+    );
+
+    let response: CreateInteractionResponse = CreateInteractionResponse::Message(
+        CreateInteractionResponseMessage::new()
+            .embed(
+                CreateEmbed::new()
+                    .title("Server settings")
+                    .description(response)
+                    .color(2326507),
+            )
+            .ephemeral(false),
+    );
+    if let Err(err) = command.create_response(&ctx, response).await {
+        println!("Error responding to slash command: {err:?}");
+    }
+
+    Ok(())
+}
+
+pub fn register() -> CreateCommand {
+    let limit = |name: &str, description: &str| {
+        CreateCommandOption::new(CommandOptionType::Integer, name, description).min_int_value(1)
+    };
+    CreateCommand::new("server_settings")
+        .description("Set or view the defaults Lumi uses across this server")
+        .default_member_permissions(Permissions::MANAGE_GUILD)
+        .dm_permission(false)
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::String,
+                "chat_mode",
+                "The chat mode channels use unless they pick their own",
+            )
+            .add_string_choice("Free Response", "free_response")
+            .add_string_choice("Mentions Only", "mentions_only")
+            .add_string_choice("Mentions Only All Context", "mentions_only_all_context")
+            .add_string_choice("Continue In Thread", "continue_in_thread"),
+        )
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::String,
+            "system_prompt",
+            "The name of the system prompt channels use unless they pick their own",
+        ))
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::String,
+                "model",
+                "The model channels chat with unless they pick their own",
+            )
+            .set_autocomplete(true),
+        )
+        .add_option(limit(
+            "user_messages_per_minute",
+            "How many messages each member can send Lumi per minute",
+        ))
+        .add_option(limit(
+            "channel_completions_per_hour",
+            "How many completions Lumi makes per channel per hour",
+        ))
+        .add_option(limit(
+            "daily_tokens",
+            "How many tokens Lumi uses in this server per day",
+        ))
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::Number,
+                "daily_cost",
+                "How many USD Lumi spends in this server per day",
+            )
+            .min_number_value(0.0),
+        )
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::Boolean,
+            "reset",
+            "Go back to the default settings before applying the other options",
+        ))
+}
@@ -1,55 +1,143 @@
 use eyre::bail;
-use indoc::indoc;
 use serenity::all::*;
 
 use crate::{db, handler::Handler};
 
+const MODAL_PREFIX: &str = "system_prompt:";
+
 pub async fn run(
     ctx: &Context,
     command: &CommandInteraction,
     handler: &Handler,
 ) -> eyre::Result<()> {
-    let response = if let Some(ResolvedOption {
-        value: ResolvedValue::String(prompt),
+    let options = command.data.options();
+    let Some(ResolvedOption {
+        name: subcommand,
+        value: ResolvedValue::SubCommand(options),
         ..
-    }) = command.data.options().first().as_ref()
-    {
-        let prompt: Option<db::SystemPrompt> = sqlx::query_as(indoc! {"
-            SELECT *
-            FROM system_prompts
-            WHERE name = $1;
-        "})
-        .bind(prompt)
-        .fetch_optional(&handler.db)
-        .await?;
-        let Some(prompt) = prompt else {
-            bail!("Could not find a system prompt for the given name");
-        };
-        sqlx::query(indoc! {"
-            UPDATE channels
-            SET system_prompt = $1
-            WHERE id = $2;
-        "})
-        .bind(prompt.id)
-        .bind(command.channel_id.get() as i64)
-        .execute(&handler.db)
-        .await?;
-        format!("Set Lumi's system prompt to *{}*", prompt.name)
-    } else {
-        let current_prompt: Option<db::SystemPrompt> = sqlx::query_as(indoc! {"
-            SELECT sp.*
-            FROM channels ch
-            JOIN system_prompts sp ON ch.system_prompt = sp.id
-            WHERE ch.id = $1;
-        "})
-        .bind(command.channel_id.get() as i64)
-        .fetch_optional(&handler.db)
-        .await?;
-        if let Some(current_prompt) = current_prompt {
-            format!("Lumi's current system prompt is *{}*", current_prompt.name)
-        } else {
-            "Lumi does not have a system prompt set for this channel".into()
+    }) = options.first()
+    else {
+        bail!("Missing subcommand");
+    };
+    let name = options.iter().find_map(|option| match option {
+        ResolvedOption {
+            name: "name",
+            value: ResolvedValue::String(name),
+            ..
+        } => Some(*name),
+        _ => None,
+    });
+
+    let mut conn = handler.db.acquire().await?;
+    let response = match (*subcommand, name) {
+        ("set", Some(name)) => {
+            let prompt = find(&mut conn.conn(), name).await?;
+            if prompt.id == db::SOCIAL_PROMPT_ID {
+                bail!("The social prompt is reserved for deciding when to reply");
+            }
+            conn.conn()
+                .ensure_channel(command.channel_id, command.guild_id)
+                .await?;
+            conn.conn()
+                .set_system_prompt(command.channel_id, Some(prompt.id))
+                .await?;
+            format!("Set Lumi's system prompt to *{}*", prompt.name)
+        }
+        ("unset", _) => {
+            conn.conn()
+                .ensure_channel(command.channel_id, command.guild_id)
+                .await?;
+            conn.conn()
+                .set_system_prompt(command.channel_id, None)
+                .await?;
+            let Some(channel) = conn.conn().channel(command.channel_id).await? else {
+                bail!("Channel was not stored");
+            };
+            let prompt = conn.conn().system_prompt(channel.system_prompt).await?;
+            format!(
+                "Lumi now follows the system prompt from {}, currently *{}*",
+                channel.inherited_from(),
+                prompt.name
+            )
+        }
+        ("show", Some(name)) => {
+            let prompt = find(&mut conn.conn(), name).await?;
+            describe(&prompt)
+        }
+        ("show", None) => {
+            conn.conn()
+                .ensure_channel(command.channel_id, command.guild_id)
+                .await?;
+            let Some(channel) = conn.conn().channel(command.channel_id).await? else {
+                bail!("Channel was not stored");
+            };
+            let current_prompt = conn.conn().system_prompt(channel.system_prompt).await?;
+            if channel.inherits_system_prompt {
+                format!(
+                    "Lumi's current system prompt, from {}, is {}",
+                    channel.inherited_from(),
+                    describe(&current_prompt)
+                )
+            } else {
+                format!(
+                    "Lumi's current system prompt is {}",
+                    describe(&current_prompt)
+                )
+            }
         }
+        ("list", _) => {
+            let prompts = conn.conn().system_prompts().await?;
+            let mut response = String::new();
+            for prompt in prompts {
+                let line = format!("- *{}*\n", prompt.name);
+                if response.len() + line.len() > 4000 {
+                    response.push_str("- ...");
+                    break;
+                }
+                response.push_str(&line);
+            }
+            response
+        }
+        ("create", Some(name)) => {
+            if conn.conn().system_prompt_by_name(name).await?.is_some() {
+                bail!("A system prompt with that name already exists");
+            }
+            let modal = modal(
+                format!("{MODAL_PREFIX}create:{name}"),
+                "Create system prompt",
+                None,
+            );
+            command
+                .create_response(&ctx, CreateInteractionResponse::Modal(modal))
+                .await?;
+            return Ok(());
+        }
+        ("edit", Some(name)) => {
+            let prompt = find(&mut conn.conn(), name).await?;
+            if prompt.id == db::SOCIAL_PROMPT_ID || prompt.id == db::DEFAULT_PROMPT_ID {
+                bail!("The built-in system prompts can't be edited from Discord");
+            }
+            let modal = modal(
+                format!("{MODAL_PREFIX}edit:{}", prompt.id),
+                "Edit system prompt",
+                Some(prompt.contents),
+            );
+            command
+                .create_response(&ctx, CreateInteractionResponse::Modal(modal))
+                .await?;
+            return Ok(());
+        }
+        ("delete", Some(name)) => {
+            let prompt = find(&mut conn.conn(), name).await?;
+            if prompt.id == db::SOCIAL_PROMPT_ID || prompt.id == db::DEFAULT_PROMPT_ID {
+                bail!("The built-in system prompts can't be deleted");
+            }
+            let mut transaction = handler.db.begin().await?;
+            transaction.conn().delete_system_prompt(prompt.id).await?;
+            transaction.commit().await?;
+            format!("Deleted system prompt *{}*", prompt.name)
+        }
+        _ => bail!("Invalid subcommand options"),
     };
 
     let response: CreateInteractionResponse = CreateInteractionResponse::Message(
@@ -69,15 +157,165 @@ pub async fn run(
     Ok(())
 }
 
+pub fn handles(modal: &ModalInteraction) -> bool {
+    modal.data.custom_id.starts_with(MODAL_PREFIX)
+}
+
+pub async fn submit(
+    ctx: &Context,
+    modal: &ModalInteraction,
+    handler: &Handler,
+) -> eyre::Result<()> {
+    // Only owners can open these modals, but the submission is checked again rather than trusted
+    if !handler
+        .config
+        .read()
+        .await
+        .discord
+        .owners
+        .contains(&modal.user.id.get())
+    {
+        bail!("Only Lumi's owners can change system prompts");
+    }
+    let Some(contents) = modal
+        .data
+        .components
+        .iter()
+        .flat_map(|row| &row.components)
+        .find_map(|component| match component {
+            ActionRowComponent::InputText(InputText {
+                custom_id, value, ..
+            }) if custom_id == "contents" => value.to_owned(),
+            _ => None,
+        })
+    else {
+        bail!("Missing system prompt contents");
+    };
+
+    let mut conn = handler.db.acquire().await?;
+    let action = modal.data.custom_id.trim_start_matches(MODAL_PREFIX);
+    let response = if let Some(name) = action.strip_prefix("create:") {
+        match conn.conn().create_system_prompt(name, &contents).await {
+            Ok(_) => format!("Created system prompt *{name}*"),
+            Err(err)
+                if err
+                    .downcast_ref::<sqlx::Error>()
+                    .and_then(|err| err.as_database_error())
+                    .is_some_and(|err| err.is_unique_violation()) =>
+            {
+                bail!("A system prompt with that name already exists")
+            }
+            Err(err) => return Err(err),
+        }
+    } else if let Some(id) = action.strip_prefix("edit:") {
+        let prompt = conn.conn().system_prompt(id.parse()?).await?;
+        if prompt.id == db::SOCIAL_PROMPT_ID || prompt.id == db::DEFAULT_PROMPT_ID {
+            bail!("The built-in system prompts can't be edited from Discord");
+        }
+        conn.conn()
+            .update_system_prompt(prompt.id, &contents)
+            .await?;
+        format!("Updated system prompt *{}*", prompt.name)
+    } else {
+        bail!("Unknown system prompt modal");
+    };
+
+    let response: CreateInteractionResponse = CreateInteractionResponse::Message(
+        CreateInteractionResponseMessage::new()
+            .embed(
+                CreateEmbed::new()
+                    .title("System prompt")
+                    .description(response)
+                    .color(2326507),
+            )
+            .ephemeral(false),
+    );
+    if let Err(err) = modal.create_response(&ctx, response).await {
+        println!("Error responding to modal: {err:?}");
+    }
+
+    Ok(())
+}
+
 pub fn register() -> CreateCommand {
+    let name = |description: &str| {
+        CreateCommandOption::new(CommandOptionType::String, "name", description)
+            .max_length(64)
+            .required(true)
+    };
     CreateCommand::new("system_prompt")
-        .description("View or change Lumi's system prompt in the current channel")
+        .description("View, change or manage Lumi's system prompts")
+        .default_member_permissions(Permissions::MANAGE_CHANNELS)
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "set",
+                "Change Lumi's system prompt in the current channel",
+            )
+            .add_sub_option(name("The system prompt for Lumi to use")),
+        )
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::SubCommand,
+            "unset",
+            "Use the server's or parent channel's system prompt in the current channel again",
+        ))
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "show",
+                "Show a system prompt, or the one used in the current channel",
+            )
+            .add_sub_option(name("The system prompt to show").required(false)),
+        )
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::SubCommand,
+            "list",
+            "List every system prompt",
+        ))
         .add_option(
             CreateCommandOption::new(
-                CommandOptionType::String,
-                "prompt",
-                "The system prompt for Lumi to use",
+                CommandOptionType::SubCommand,
+                "create",
+                "Create a new system prompt",
             )
-            .required(false),
+            .add_sub_option(name("The name of the new system prompt")),
         )
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "edit",
+                "Edit an existing system prompt",
+            )
+            .add_sub_option(name("The system prompt to edit")),
+        )
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "delete",
+                "Delete a system prompt, channels using it go back to the server's or the default",
+            )
+            .add_sub_option(name("The system prompt to delete")),
+        )
+}
+
+async fn find(conn: &mut db::Connection<'_>, name: &str) -> eyre::Result<db::SystemPrompt> {
+    let Some(prompt) = conn.system_prompt_by_name(name).await? else {
+        bail!("Could not find a system prompt for the given name");
+    };
+    Ok(prompt)
+}
+
+fn describe(prompt: &db::SystemPrompt) -> String {
+    let contents = prompt.contents.chars().take(3800).collect::<String>();
+    format!("*{}*\n>>> {contents}", prompt.name)
+}
+
+fn modal(custom_id: String, title: &str, contents: Option<String>) -> CreateModal {
+    let mut input = CreateInputText::new(InputTextStyle::Paragraph, "Contents", "contents")
+        .max_length(4000)
+        .required(true);
+    if let Some(contents) = contents {
+        input = input.value(contents.chars().take(4000).collect::<String>());
+    }
+    CreateModal::new(custom_id, title).components(vec![CreateActionRow::InputText(input)])
 }
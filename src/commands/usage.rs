@@ -0,0 +1,123 @@
+use eyre::bail;
+use serenity::all::*;
+
+use crate::{db, handler::Handler};
+
+pub async fn run(
+    ctx: &Context,
+    command: &CommandInteraction,
+    handler: &Handler,
+) -> eyre::Result<()> {
+    let mut range = "day";
+    let mut channel = None;
+    let mut user = None;
+    for option in command.data.options() {
+        match (option.name, option.value) {
+            ("range", ResolvedValue::String(value)) => range = value,
+            ("channel", ResolvedValue::Channel(value)) => channel = Some(value.id),
+            ("user", ResolvedValue::User(value, _)) => user = Some(value.id),
+            _ => bail!("Invalid command options"),
+        }
+    }
+    let (seconds, label) = match range {
+        "day" => (86400, "the last 24 hours"),
+        "week" => (7 * 86400, "the last 7 days"),
+        "month" => (30 * 86400, "the last 30 days"),
+        "all" => (i64::MAX, "all time"),
+        _ => bail!("Invalid range"),
+    };
+    // Outside of servers usage can only be looked up for the current channel
+    let filter = db::UsageFilter {
+        guild: command.guild_id,
+        channel: channel.or(command.guild_id.is_none().then_some(command.channel_id)),
+        sender: user,
+        since: Timestamp::now()
+            .unix_timestamp()
+            .saturating_sub(seconds)
+            .max(0) as u64,
+        completions_only: false,
+    };
+    let totals = handler
+        .db
+        .acquire()
+        .await?
+        .conn()
+        .usage_totals(&filter)
+        .await?;
+
+    let response = if totals.is_empty() {
+        format!("No usage was recorded in {label}")
+    } else {
+        let config = handler.config.read().await;
+        let mut response = format!("Usage in {label}:\n");
+        let mut total_cost = 0.0;
+        let mut unpriced = false;
+        for total in &totals {
+            let cost = config.openrouter.cost(total);
+            total_cost += cost.unwrap_or_default();
+            unpriced |= cost.is_none();
+            let line = format!(
+                "- *{}*: {} requests, {} prompt and {} completion tokens{}\n",
+                total.model,
+                total.requests,
+                total.prompt_tokens,
+                total.completion_tokens,
+                cost.map(|cost| format!(", ~${cost:.4}"))
+                    .unwrap_or_default()
+            );
+            if response.len() + line.len() > 3800 {
+                response.push_str("- ...\n");
+                break;
+            }
+            response.push_str(&line);
+        }
+        response.push_str(&format!("\nEstimated cost: **~${total_cost:.4}**"));
+        if unpriced {
+            response.push_str("\n-# Models without a configured price aren't included");
+        }
+        response
+    };
+
+    let response: CreateInteractionResponse = CreateInteractionResponse::Message(
+        CreateInteractionResponseMessage::new()
+            .embed(
+                CreateEmbed::new()
+                    .title("Usage")
+                    .description(response)
+                    .color(2326507),
+            )
+            .ephemeral(true),
+    );
+    if let Err(err) = command.create_response(&ctx, response).await {
+        println!("Error responding to slash command: {err:?}");
+    }
+
+    Ok(())
+}
+
+pub fn register() -> CreateCommand {
+    CreateCommand::new("usage")
+        .description("Show the tokens Lumi used and what they cost")
+        .default_member_permissions(Permissions::MANAGE_GUILD)
+        .add_option(
+            CreateCommandOption::new(
+                CommandOptionType::String,
+                "range",
+                "How far back to count, the last 24 hours by default",
+            )
+            .add_string_choice("Last 24 hours", "day")
+            .add_string_choice("Last 7 days", "week")
+            .add_string_choice("Last 30 days", "month")
+            .add_string_choice("All time", "all"),
+        )
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::Channel,
+            "channel",
+            "Only count usage in this channel",
+        ))
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::User,
+            "user",
+            "Only count usage caused by this user",
+        ))
+}
@@ -0,0 +1,74 @@
+use eyre::bail;
+use serenity::all::*;
+
+use crate::handler::Handler;
+
+pub async fn run(
+    ctx: &Context,
+    command: &CommandInteraction,
+    handler: &Handler,
+) -> eyre::Result<()> {
+    let Some(ResolvedTarget::Message(message)) = command.data.target() else {
+        bail!("Missing target message");
+    };
+    let decision = handler
+        .db
+        .acquire()
+        .await?
+        .conn()
+        .decision(message.id)
+        .await?;
+
+    let embed = CreateEmbed::new().title("Why").color(2326507);
+    let embed = if let Some(decision) = decision {
+        let verdict = if decision.should_reply {
+            "Lumi decided to reply"
+        } else {
+            "Lumi decided to stay silent"
+        };
+        let mut embed = embed
+            .description(format!("{verdict} <t:{}:R>", decision.time))
+            .field(
+                "Model",
+                decision
+                    .model
+                    .as_deref()
+                    .unwrap_or("None, no decision needed"),
+                true,
+            );
+        if let Some(confidence) = decision.confidence {
+            embed = embed.field("Confidence", format!("{confidence:.2}"), true);
+        }
+        if let Some(latency) = decision.latency {
+            embed = embed.field("Latency", format!("{latency} ms"), true);
+        }
+        if let Some(reason) = decision.reason {
+            embed = embed.field(
+                "Reason",
+                reason.chars().take(1024).collect::<String>(),
+                false,
+            );
+        }
+        embed
+    } else {
+        embed.description(
+            "No decision was recorded for this message, Lumi only considers messages it can \
+            reply to and the last message of a burst",
+        )
+    };
+
+    let response: CreateInteractionResponse = CreateInteractionResponse::Message(
+        CreateInteractionResponseMessage::new()
+            .embed(embed)
+            .ephemeral(true),
+    );
+    if let Err(err) = command.create_response(&ctx, response).await {
+        println!("Error responding to context menu command: {err:?}");
+    }
+
+    Ok(())
+}
+
+pub fn register() -> CreateCommand {
+    CreateCommand::new("why").kind(CommandType::Message)
+}
@@ -0,0 +1,87 @@
+use indoc::indoc;
+
+use crate::db::Database;
+
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    postgres: &'static str,
+    sqlite: &'static str,
+}
+
+macro_rules! migration {
+    ($version:literal, $name:literal) => {
+        Migration {
+            version: $version,
+            name: $name,
+            postgres: include_str!(concat!("../../migrations/postgres/", $name, ".sql")),
+            sqlite: include_str!(concat!("../../migrations/sqlite/", $name, ".sql")),
+        }
+    };
+}
+
+// Versions must be unique and increasing, applied migrations must never be edited
+pub const MIGRATIONS: &[Migration] = &[
+    migration!(1, "0001_initial"),
+    migration!(2, "0002_rename_mentions_self"),
+    migration!(3, "0003_unique_system_prompt_names"),
+    migration!(4, "0004_command_roles"),
+    migration!(5, "0005_channel_summary"),
+    migration!(6, "0006_decisions"),
+    migration!(7, "0007_usage"),
+    migration!(8, "0008_channel_model_overrides"),
+    migration!(9, "0009_guilds"),
+    migration!(10, "0010_channel_parents"),
+    migration!(11, "0011_continue_in_thread"),
+    migration!(12, "0012_memories"),
+    migration!(13, "0013_embeddings"),
+    migration!(14, "0014_recall_window"),
+    migration!(15, "0015_usage_kinds"),
+    migration!(16, "0016_channel_setting_overrides"),
+];
+
+impl Database {
+    /// Applies every migration missing from `schema_migrations`, each in its own transaction
+    pub async fn migrate(&self) -> eyre::Result<Vec<&'static Migration>> {
+        match self {
+            Self::Postgres(pool) => {
+                sqlx::raw_sql(indoc! {"
+                    CREATE TABLE IF NOT EXISTS schema_migrations (
+                        version BIGINT PRIMARY KEY,
+                        name TEXT NOT NULL,
+                        applied_at BIGINT NOT NULL DEFAULT extract(epoch from now())::bigint
+                    );
+                "})
+                .execute(pool)
+                .await?;
+            }
+            Self::Sqlite(pool) => {
+                sqlx::raw_sql(indoc! {"
+                    CREATE TABLE IF NOT EXISTS schema_migrations (
+                        version INTEGER PRIMARY KEY,
+                        name TEXT NOT NULL,
+                        applied_at INTEGER NOT NULL DEFAULT (unixepoch())
+                    );
+                "})
+                .execute(pool)
+                .await?;
+            }
+        }
+
+        let mut applied = vec![];
+        for migration in MIGRATIONS {
+            let mut transaction = self.begin().await?;
+            let mut conn = transaction.conn();
+            if conn.migration_applied(migration.version).await? {
+                continue;
+            }
+            conn.apply_migration(migration.postgres, migration.sqlite)
+                .await?;
+            conn.record_migration(migration.version, migration.name)
+                .await?;
+            transaction.commit().await?;
+            applied.push(migration);
+        }
+        Ok(applied)
+    }
+}
@@ -0,0 +1,487 @@
+use std::time::Duration;
+
+use openai_api_rs::v1::chat_completion::Reasoning;
+use serenity::all::{Attachment as SerenityAttachment, ChannelId, GuildId, MessageId, UserId};
+use sqlx::{Decode, FromRow, Row, prelude::*};
+
+mod migrations;
+mod storage;
+
+pub use migrations::{MIGRATIONS, Migration};
+pub use storage::{Connection, Database, Pooled, Transaction};
+
+pub const DEFAULT_PROMPT_ID: i64 = 0;
+/// Instructs the social model, it is reserved and can't be changed from Discord
+pub const SOCIAL_PROMPT_ID: i64 = 1;
+/// How many memories Lumi keeps about a user in a guild
+pub const MAX_MEMORIES: u64 = 50;
+/// Longest memory in characters
+pub const MAX_MEMORY_LENGTH: usize = 500;
+
+pub struct SystemPrompt {
+    pub id: i64,
+    pub name: String,
+    pub contents: String,
+}
+
+pub struct Channel {
+    pub id: u64,
+    /// The channel's own chat mode, or else the one it inherits from its parent, guild or the
+    /// built-in default
+    pub chat_mode: ChatMode,
+    /// Whether the channel hasn't picked a chat mode of its own
+    pub inherits_chat_mode: bool,
+    pub context_window: u64,
+    /// Resolved the same way as the chat mode
+    pub system_prompt: i64,
+    /// Whether the channel hasn't picked a system prompt of its own
+    pub inherits_system_prompt: bool,
+    pub summary: Option<String>,
+    pub overrides: ModelOverrides,
+    /// The channel a thread was started in
+    pub parent: Option<u64>,
+    pub guild: Option<u64>,
+}
+
+impl Channel {
+    /// Where settings the channel didn't pick itself come from
+    pub fn inherited_from(&self) -> &'static str {
+        if self.parent.is_some() {
+            "the channel this thread was started in"
+        } else if self.guild.is_some() {
+            "the server settings"
+        } else {
+            "Lumi's defaults"
+        }
+    }
+}
+
+/// Overrides for the chat model's settings, every `None` falls back to the config
+#[derive(Default, Clone)]
+pub struct ModelOverrides {
+    pub model: Option<String>,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub max_tokens: Option<i64>,
+    pub reasoning: Option<Reasoning>,
+}
+
+/// Defaults for a guild's channels, every `None` falls back to the config or the built-in defaults
+pub struct Guild {
+    pub id: u64,
+    /// Used by channels that don't pick their own chat mode
+    pub chat_mode: Option<ChatMode>,
+    /// Used by channels that don't pick their own system prompt
+    pub system_prompt: Option<i64>,
+    /// Used by channels that don't override the model themselves
+    pub model: Option<String>,
+    pub user_messages_per_minute: Option<u32>,
+    pub channel_completions_per_hour: Option<u64>,
+    pub daily_tokens: Option<u64>,
+    pub daily_cost: Option<f64>,
+}
+
+#[derive(Clone)]
+pub struct Message {
+    pub id: u64,
+    pub is_self: bool,
+    pub mentions_self: bool,
+    pub sender: u64,
+    pub sender_name: String,
+    pub sender_display_name: String,
+    pub guild: Option<u64>,
+    pub channel: u64,
+    pub contents: String,
+    pub reply: Option<u64>,
+    pub time: u64,
+    pub reply_sender_name: Option<String>,
+    pub reply_contents: Option<String>,
+}
+
+pub struct NewMessage {
+    pub id: MessageId,
+    pub is_self: bool,
+    pub mentions_self: bool,
+    pub sender: UserId,
+    pub sender_name: String,
+    pub sender_display_name: String,
+    pub guild: Option<GuildId>,
+    pub channel: ChannelId,
+    pub contents: String,
+    pub reply: Option<MessageId>,
+}
+
+/// Whether Lumi decided to reply to a message, `model` is `None` when no model was asked
+pub struct Decision {
+    pub message: u64,
+    pub channel: u64,
+    pub model: Option<String>,
+    pub should_reply: bool,
+    pub confidence: Option<f64>,
+    pub reason: Option<String>,
+    /// Milliseconds the social model took to decide
+    pub latency: Option<u64>,
+    pub time: u64,
+}
+
+pub struct NewDecision {
+    pub message: MessageId,
+    pub channel: ChannelId,
+    pub model: Option<String>,
+    pub should_reply: bool,
+    pub confidence: Option<f64>,
+    pub reason: Option<String>,
+    pub latency: Option<Duration>,
+}
+
+pub struct NewUsage {
+    pub guild: Option<GuildId>,
+    pub channel: ChannelId,
+    pub sender: Option<UserId>,
+    pub kind: UsageKind,
+    pub model: String,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+/// What a request was made for
+#[derive(Clone, Copy)]
+pub enum UsageKind {
+    Chat,
+    Social,
+    Summary,
+    Embedding,
+}
+
+impl UsageKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            UsageKind::Chat => "chat",
+            UsageKind::Social => "social",
+            UsageKind::Summary => "summary",
+            UsageKind::Embedding => "embedding",
+        }
+    }
+}
+
+/// Which usage to total up, every filter that is `None` matches anything
+pub struct UsageFilter {
+    pub guild: Option<GuildId>,
+    pub channel: Option<ChannelId>,
+    pub sender: Option<UserId>,
+    pub since: u64,
+    /// Leaves out summaries and embeddings, counting only chat and social completions
+    pub completions_only: bool,
+}
+
+/// Usage of a single model summed over a filter
+pub struct UsageTotal {
+    pub model: String,
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+/// A fact Lumi remembers about a user, `guild` is `None` for direct messages
+pub struct Memory {
+    pub id: i64,
+    pub subject: u64,
+    pub guild: Option<u64>,
+    pub contents: String,
+    pub time: u64,
+}
+
+pub struct Attachment {
+    pub id: u64,
+    pub message: u64,
+    pub filename: String,
+    pub url: String,
+    pub content_type: Option<String>,
+    pub description: Option<String>,
+}
+
+impl<'r, R: Row> FromRow<'r, R> for SystemPrompt
+where
+    &'r str: sqlx::ColumnIndex<R>,
+    i64: Decode<'r, R::Database>,
+    i64: Type<R::Database>,
+    String: Decode<'r, R::Database>,
+    String: Type<R::Database>,
+{
+    fn from_row(row: &'r R) -> Result<Self, sqlx::Error> {
+        Ok(Self {
+            id: row.try_get("id")?,
+            name: row.try_get("name")?,
+            contents: row.try_get("contents")?,
+        })
+    }
+}
+
+impl<'r, R: Row> FromRow<'r, R> for Channel
+where
+    &'r str: sqlx::ColumnIndex<R>,
+    i64: Decode<'r, R::Database>,
+    i64: Type<R::Database>,
+    ChatMode: Decode<'r, R::Database>,
+    ChatMode: Type<R::Database>,
+    String: Decode<'r, R::Database>,
+    String: Type<R::Database>,
+    f64: Decode<'r, R::Database>,
+    f64: Type<R::Database>,
+    bool: Decode<'r, R::Database>,
+    bool: Type<R::Database>,
+{
+    fn from_row(row: &'r R) -> Result<Self, sqlx::Error> {
+        Ok(Self {
+            id: row.try_get::<i64, _>("id")? as _,
+            chat_mode: row.try_get("chat_mode")?,
+            inherits_chat_mode: row.try_get("inherits_chat_mode")?,
+            context_window: row.try_get::<i64, _>("context_window")? as _,
+            system_prompt: row.try_get("system_prompt")?,
+            inherits_system_prompt: row.try_get("inherits_system_prompt")?,
+            summary: row.try_get("summary")?,
+            overrides: ModelOverrides::from_row(row)?,
+            parent: row.try_get::<Option<i64>, _>("parent")?.map(|v| v as _),
+            guild: row.try_get::<Option<i64>, _>("guild")?.map(|v| v as _),
+        })
+    }
+}
+
+impl<'r, R: Row> FromRow<'r, R> for ModelOverrides
+where
+    &'r str: sqlx::ColumnIndex<R>,
+    i64: Decode<'r, R::Database>,
+    i64: Type<R::Database>,
+    f64: Decode<'r, R::Database>,
+    f64: Type<R::Database>,
+    String: Decode<'r, R::Database>,
+    String: Type<R::Database>,
+{
+    fn from_row(row: &'r R) -> Result<Self, sqlx::Error> {
+        // Reasoning settings are stored as the JSON sent to OpenRouter
+        let reasoning = row
+            .try_get::<Option<String>, _>("reasoning")?
+            .map(|reasoning| serde_json::from_str(&reasoning))
+            .transpose()
+            .map_err(|err| sqlx::Error::Decode(Box::new(err)))?;
+        Ok(Self {
+            model: row.try_get("model")?,
+            temperature: row.try_get("temperature")?,
+            top_p: row.try_get("top_p")?,
+            max_tokens: row.try_get("max_tokens")?,
+            reasoning,
+        })
+    }
+}
+
+impl<'r, R: Row> FromRow<'r, R> for Guild
+where
+    &'r str: sqlx::ColumnIndex<R>,
+    i64: Decode<'r, R::Database>,
+    i64: Type<R::Database>,
+    ChatMode: Decode<'r, R::Database>,
+    ChatMode: Type<R::Database>,
+    String: Decode<'r, R::Database>,
+    String: Type<R::Database>,
+    f64: Decode<'r, R::Database>,
+    f64: Type<R::Database>,
+{
+    fn from_row(row: &'r R) -> Result<Self, sqlx::Error> {
+        Ok(Self {
+            id: row.try_get::<i64, _>("id")? as _,
+            chat_mode: row.try_get("chat_mode")?,
+            system_prompt: row.try_get("system_prompt")?,
+            model: row.try_get("model")?,
+            user_messages_per_minute: row
+                .try_get::<Option<i64>, _>("user_messages_per_minute")?
+                .map(|v| v as _),
+            channel_completions_per_hour: row
+                .try_get::<Option<i64>, _>("channel_completions_per_hour")?
+                .map(|v| v as _),
+            daily_tokens: row
+                .try_get::<Option<i64>, _>("daily_tokens")?
+                .map(|v| v as _),
+            daily_cost: row.try_get("daily_cost")?,
+        })
+    }
+}
+
+impl<'r, R: Row> FromRow<'r, R> for Message
+where
+    &'r str: sqlx::ColumnIndex<R>,
+    i64: Decode<'r, R::Database>,
+    i64: Type<R::Database>,
+    bool: Decode<'r, R::Database>,
+    bool: Type<R::Database>,
+    String: Decode<'r, R::Database>,
+    String: Type<R::Database>,
+{
+    fn from_row(row: &'r R) -> Result<Self, sqlx::Error> {
+        Ok(Self {
+            id: row.try_get::<i64, _>("id")? as _,
+            is_self: row.try_get("is_self")?,
+            mentions_self: row.try_get("mentions_self")?,
+            sender: row.try_get::<i64, _>("sender")? as _,
+            sender_name: row.try_get("sender_name")?,
+            sender_display_name: row.try_get("sender_display_name")?,
+            guild: row.try_get::<Option<i64>, _>("guild")?.map(|v| v as _),
+            channel: row.try_get::<i64, _>("channel")? as _,
+            contents: row.try_get("contents")?,
+            reply: row.try_get::<Option<i64>, _>("reply")?.map(|v| v as _),
+            time: row.try_get::<i64, _>("time")? as _,
+            reply_sender_name: row.try_get("reply_sender_name")?,
+            reply_contents: row.try_get("reply_contents")?,
+        })
+    }
+}
+
+impl<'r, R: Row> FromRow<'r, R> for Attachment
+where
+    &'r str: sqlx::ColumnIndex<R>,
+    i64: Decode<'r, R::Database>,
+    i64: Type<R::Database>,
+    String: Decode<'r, R::Database>,
+    String: Type<R::Database>,
+{
+    fn from_row(row: &'r R) -> Result<Self, sqlx::Error> {
+        Ok(Self {
+            id: row.try_get::<i64, _>("id")? as _,
+            message: row.try_get::<i64, _>("message")? as _,
+            filename: row.try_get("filename")?,
+            url: row.try_get("url")?,
+            content_type: row.try_get("content_type")?,
+            description: row.try_get("description")?,
+        })
+    }
+}
+
+impl<'r, R: Row> FromRow<'r, R> for Memory
+where
+    &'r str: sqlx::ColumnIndex<R>,
+    i64: Decode<'r, R::Database>,
+    i64: Type<R::Database>,
+    String: Decode<'r, R::Database>,
+    String: Type<R::Database>,
+{
+    fn from_row(row: &'r R) -> Result<Self, sqlx::Error> {
+        Ok(Self {
+            id: row.try_get("id")?,
+            subject: row.try_get::<i64, _>("subject")? as _,
+            guild: row.try_get::<Option<i64>, _>("guild")?.map(|v| v as _),
+            contents: row.try_get("contents")?,
+            time: row.try_get::<i64, _>("time")? as _,
+        })
+    }
+}
+
+impl<'r, R: Row> FromRow<'r, R> for Decision
+where
+    &'r str: sqlx::ColumnIndex<R>,
+    i64: Decode<'r, R::Database>,
+    i64: Type<R::Database>,
+    f64: Decode<'r, R::Database>,
+    f64: Type<R::Database>,
+    bool: Decode<'r, R::Database>,
+    bool: Type<R::Database>,
+    String: Decode<'r, R::Database>,
+    String: Type<R::Database>,
+{
+    fn from_row(row: &'r R) -> Result<Self, sqlx::Error> {
+        Ok(Self {
+            message: row.try_get::<i64, _>("message")? as _,
+            channel: row.try_get::<i64, _>("channel")? as _,
+            model: row.try_get("model")?,
+            should_reply: row.try_get("should_reply")?,
+            confidence: row.try_get("confidence")?,
+            reason: row.try_get("reason")?,
+            latency: row.try_get::<Option<i64>, _>("latency")?.map(|v| v as _),
+            time: row.try_get::<i64, _>("time")? as _,
+        })
+    }
+}
+
+impl<'r, R: Row> FromRow<'r, R> for UsageTotal
+where
+    &'r str: sqlx::ColumnIndex<R>,
+    i64: Decode<'r, R::Database>,
+    i64: Type<R::Database>,
+    String: Decode<'r, R::Database>,
+    String: Type<R::Database>,
+{
+    fn from_row(row: &'r R) -> Result<Self, sqlx::Error> {
+        Ok(Self {
+            model: row.try_get("model")?,
+            requests: row.try_get::<i64, _>("requests")? as _,
+            prompt_tokens: row.try_get::<i64, _>("prompt_tokens")? as _,
+            completion_tokens: row.try_get::<i64, _>("completion_tokens")? as _,
+        })
+    }
+}
+
+impl Attachment {
+    pub fn new(message: MessageId, attachment: &SerenityAttachment) -> Self {
+        Self {
+            id: attachment.id.get(),
+            message: message.get(),
+            filename: attachment.filename.to_owned(),
+            url: attachment.url.to_owned(),
+            content_type: attachment.content_type.to_owned(),
+            description: attachment.description.to_owned(),
+        }
+    }
+
+    pub fn is_image(&self) -> bool {
+        self.content_type
+            .as_ref()
+            .is_some_and(|content_type| content_type.starts_with("image/"))
+    }
+
+    pub fn placeholder(&self) -> String {
+        let kind = if self.is_image() {
+            "image"
+        } else {
+            "attachment"
+        };
+        match &self.description {
+            Some(description) => format!("[{kind}: {}, alt text: {description}]", self.filename),
+            None => format!("[{kind}: {}]", self.filename),
+        }
+    }
+}
+
+#[derive(Debug, sqlx::Type, serde::Serialize, serde::Deserialize, PartialEq)]
+#[sqlx(type_name = "chat_mode", rename_all = "snake_case")]
+pub enum ChatMode {
+    FreeResponse,
+    MentionsOnly,
+    MentionsOnlyAllContext,
+    /// Mentions open a thread from the message and Lumi carries on the conversation there
+    ContinueInThread,
+}
+
+impl std::fmt::Display for ChatMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ChatMode::FreeResponse => "Free Response",
+            ChatMode::MentionsOnly => "Mentions Only",
+            ChatMode::MentionsOnlyAllContext => "Mentions Only All Context",
+            ChatMode::ContinueInThread => "Continue In Thread",
+        })
+    }
+}
+
+impl std::str::FromStr for ChatMode {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(match s {
+            "free_response" => Self::FreeResponse,
+            "mentions_only" => Self::MentionsOnly,
+            "mentions_only_all_context" => Self::MentionsOnlyAllContext,
+            "continue_in_thread" => Self::ContinueInThread,
+            _ => {
+                eyre::bail!("Invalid chat mode string");
+            }
+        })
+    }
+}
@@ -0,0 +1,1241 @@
+use std::{str::FromStr, time::Duration};
+
+use indoc::indoc;
+use serenity::all::{ChannelId, GuildId, MessageId, RoleId, UserId};
+use sqlx::{
+    PgConnection, PgPool, Postgres, Sqlite, SqliteConnection, SqlitePool,
+    pool::PoolConnection,
+    postgres::PgPoolOptions,
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions},
+};
+
+use crate::{
+    ConfigDatabase, ConfigDatabaseBackend,
+    db::{
+        Attachment, Channel, ChatMode, DEFAULT_PROMPT_ID, Decision, Guild, MAX_MEMORIES, Memory,
+        Message, ModelOverrides, NewDecision, NewMessage, NewUsage, SystemPrompt, UsageFilter,
+        UsageTotal,
+    },
+};
+
+#[derive(Clone)]
+pub enum Database {
+    Postgres(PgPool),
+    Sqlite(SqlitePool),
+}
+
+pub enum Pooled {
+    Postgres(PoolConnection<Postgres>),
+    Sqlite(PoolConnection<Sqlite>),
+}
+
+pub enum Transaction {
+    Postgres(sqlx::Transaction<'static, Postgres>),
+    Sqlite(sqlx::Transaction<'static, Sqlite>),
+}
+
+/// A connection to either backend, every query lives here so it can run inside or outside of a
+/// transaction
+pub enum Connection<'c> {
+    Postgres(&'c mut PgConnection),
+    Sqlite(&'c mut SqliteConnection),
+}
+
+// Runs the same query against whichever backend the connection belongs to, the body is expanded
+// once per backend so it only has to type check against both
+macro_rules! dispatch {
+    ($conn:expr, $c:ident => $body:expr) => {
+        match $conn {
+            Connection::Postgres($c) => $body,
+            Connection::Sqlite($c) => $body,
+        }
+    };
+}
+
+impl Database {
+    pub async fn connect(config: &ConfigDatabase) -> eyre::Result<Self> {
+        Ok(match config.backend {
+            ConfigDatabaseBackend::Postgres => {
+                Self::Postgres(PgPoolOptions::new().connect(&config.url).await?)
+            }
+            ConfigDatabaseBackend::Sqlite => {
+                let options = SqliteConnectOptions::from_str(&config.url)?
+                    .create_if_missing(true)
+                    .foreign_keys(true)
+                    .journal_mode(SqliteJournalMode::Wal)
+                    // Writers queue on a single database lock, transactions only hold it for a
+                    // few statements and never across a request to Discord or a model
+                    .busy_timeout(Duration::from_secs(10));
+                Self::Sqlite(SqlitePoolOptions::new().connect_with(options).await?)
+            }
+        })
+    }
+
+    pub async fn acquire(&self) -> eyre::Result<Pooled> {
+        Ok(match self {
+            Self::Postgres(pool) => Pooled::Postgres(pool.acquire().await?),
+            Self::Sqlite(pool) => Pooled::Sqlite(pool.acquire().await?),
+        })
+    }
+
+    pub async fn begin(&self) -> eyre::Result<Transaction> {
+        Ok(match self {
+            Self::Postgres(pool) => Transaction::Postgres(pool.begin().await?),
+            // Take the write lock up front, SQLite has no row locks to upgrade to later
+            Self::Sqlite(pool) => Transaction::Sqlite(pool.begin_with("BEGIN IMMEDIATE").await?),
+        })
+    }
+}
+
+impl Pooled {
+    pub fn conn(&mut self) -> Connection<'_> {
+        match self {
+            Self::Postgres(conn) => Connection::Postgres(conn),
+            Self::Sqlite(conn) => Connection::Sqlite(conn),
+        }
+    }
+}
+
+impl Transaction {
+    pub fn conn(&mut self) -> Connection<'_> {
+        match self {
+            Self::Postgres(transaction) => Connection::Postgres(transaction),
+            Self::Sqlite(transaction) => Connection::Sqlite(transaction),
+        }
+    }
+
+    pub async fn commit(self) -> eyre::Result<()> {
+        match self {
+            Self::Postgres(transaction) => transaction.commit().await?,
+            Self::Sqlite(transaction) => transaction.commit().await?,
+        }
+        Ok(())
+    }
+}
+
+impl Connection<'_> {
+    pub async fn migration_applied(&mut self, version: i64) -> eyre::Result<bool> {
+        let applied: Option<i64> = dispatch!(self, c => {
+            sqlx::query_scalar(indoc! {"
+                SELECT version
+                FROM schema_migrations
+                WHERE version = $1;
+            "})
+            .bind(version)
+            .fetch_optional(&mut **c)
+            .await?
+        });
+        Ok(applied.is_some())
+    }
+
+    pub async fn apply_migration(&mut self, postgres: &str, sqlite: &str) -> eyre::Result<()> {
+        match self {
+            Connection::Postgres(c) => sqlx::raw_sql(postgres)
+                .execute(&mut **c)
+                .await?
+                .rows_affected(),
+            Connection::Sqlite(c) => sqlx::raw_sql(sqlite)
+                .execute(&mut **c)
+                .await?
+                .rows_affected(),
+        };
+        Ok(())
+    }
+
+    pub async fn record_migration(&mut self, version: i64, name: &str) -> eyre::Result<()> {
+        dispatch!(self, c => {
+            sqlx::query(indoc! {"
+                INSERT INTO schema_migrations (version, name)
+                VALUES ($1, $2);
+            "})
+            .bind(version)
+            .bind(name)
+            .execute(&mut **c)
+            .await?
+            .rows_affected()
+        });
+        Ok(())
+    }
+
+    /// Looks up a channel with its chat mode and system prompt resolved. Settings a channel didn't
+    /// pick come from the channel a thread was started in, then the guild, then the defaults.
+    /// Threads can't open threads of their own, so they answer freely instead
+    pub async fn channel(&mut self, id: ChannelId) -> eyre::Result<Option<Channel>> {
+        Ok(dispatch!(self, c => {
+            sqlx::query_as(indoc! {"
+                SELECT
+                    c.id,
+                    COALESCE(
+                        c.chat_mode,
+                        CASE
+                            WHEN c.parent IS NULL THEN g.chat_mode
+                            WHEN COALESCE(p.chat_mode, g.chat_mode) = 'continue_in_thread' THEN 'free_response'
+                            ELSE COALESCE(p.chat_mode, g.chat_mode)
+                        END,
+                        'mentions_only_all_context'
+                    ) AS chat_mode,
+                    c.chat_mode IS NULL AS inherits_chat_mode,
+                    c.context_window,
+                    COALESCE(c.system_prompt, p.system_prompt, g.system_prompt, $2) AS system_prompt,
+                    c.system_prompt IS NULL AS inherits_system_prompt,
+                    c.summary,
+                    c.model,
+                    c.temperature,
+                    c.top_p,
+                    c.max_tokens,
+                    c.reasoning,
+                    c.parent,
+                    c.guild
+                FROM channels c
+                LEFT JOIN channels p ON p.id = c.parent
+                LEFT JOIN guilds g ON g.id = c.guild
+                WHERE c.id = $1;
+            "})
+            .bind(id.get() as i64)
+            .bind(DEFAULT_PROMPT_ID)
+            .fetch_optional(&mut **c)
+            .await?
+        }))
+    }
+
+    /// Adds a channel the first time it is seen, remembering its guild so it picks up the guild's
+    /// defaults
+    pub async fn ensure_channel(
+        &mut self,
+        id: ChannelId,
+        guild: Option<GuildId>,
+    ) -> eyre::Result<()> {
+        dispatch!(self, c => {
+            sqlx::query(indoc! {"
+                INSERT INTO channels (id, guild)
+                VALUES ($1, $2)
+                ON CONFLICT (id)
+                DO UPDATE SET
+                    guild = COALESCE(channels.guild, excluded.guild);
+            "})
+            .bind(id.get() as i64)
+            .bind(guild.map(|guild| guild.get() as i64))
+            .execute(&mut **c)
+            .await?
+            .rows_affected()
+        });
+        Ok(())
+    }
+
+    /// Adds a thread the first time it is seen, it follows its parent's chat mode and system prompt
+    /// until it picks its own. The parent has to be stored already
+    pub async fn ensure_thread(&mut self, id: ChannelId, parent: ChannelId) -> eyre::Result<()> {
+        dispatch!(self, c => {
+            sqlx::query(indoc! {"
+                INSERT INTO channels (id, parent, guild)
+                SELECT $1, id, guild
+                FROM channels
+                WHERE id = $2
+                ON CONFLICT (id) DO NOTHING;
+            "})
+            .bind(id.get() as i64)
+            .bind(parent.get() as i64)
+            .execute(&mut **c)
+            .await?
+            .rows_affected()
+        });
+        Ok(())
+    }
+
+    pub async fn guild(&mut self, id: GuildId) -> eyre::Result<Option<Guild>> {
+        Ok(dispatch!(self, c => {
+            sqlx::query_as(indoc! {"
+                SELECT *
+                FROM guilds
+                WHERE id = $1;
+            "})
+            .bind(id.get() as i64)
+            .fetch_optional(&mut **c)
+            .await?
+        }))
+    }
+
+    pub async fn set_guild(&mut self, guild: &Guild) -> eyre::Result<()> {
+        dispatch!(self, c => {
+            sqlx::query(indoc! {"
+                INSERT INTO guilds (
+                    id, chat_mode, system_prompt, model, user_messages_per_minute,
+                    channel_completions_per_hour, daily_tokens, daily_cost
+                ) VALUES (
+                    $1, $2, $3, $4, $5, $6, $7, $8
+                )
+                ON CONFLICT (id)
+                DO UPDATE SET
+                    chat_mode = $2,
+                    system_prompt = $3,
+                    model = $4,
+                    user_messages_per_minute = $5,
+                    channel_completions_per_hour = $6,
+                    daily_tokens = $7,
+                    daily_cost = $8;
+            "})
+            .bind(guild.id as i64)
+            .bind(&guild.chat_mode)
+            .bind(guild.system_prompt)
+            .bind(&guild.model)
+            .bind(guild.user_messages_per_minute.map(|v| v as i64))
+            .bind(guild.channel_completions_per_hour.map(|v| v as i64))
+            .bind(guild.daily_tokens.map(|v| v as i64))
+            .bind(guild.daily_cost)
+            .execute(&mut **c)
+            .await?
+            .rows_affected()
+        });
+        Ok(())
+    }
+
+    /// Sets a channel's own chat mode, `None` makes it follow its parent or guild again
+    pub async fn set_chat_mode(
+        &mut self,
+        id: ChannelId,
+        chat_mode: Option<&ChatMode>,
+    ) -> eyre::Result<()> {
+        dispatch!(self, c => {
+            sqlx::query(indoc! {"
+                INSERT INTO channels (id, chat_mode)
+                VALUES ($1, $2)
+                ON CONFLICT (id)
+                DO UPDATE SET
+                    chat_mode = $2;
+            "})
+            .bind(id.get() as i64)
+            .bind(chat_mode)
+            .execute(&mut **c)
+            .await?
+            .rows_affected()
+        });
+        Ok(())
+    }
+
+    pub async fn set_model_overrides(
+        &mut self,
+        id: ChannelId,
+        overrides: &ModelOverrides,
+    ) -> eyre::Result<()> {
+        let reasoning = overrides
+            .reasoning
+            .as_ref()
+            .map(serde_json::to_string)
+            .transpose()?;
+        dispatch!(self, c => {
+            sqlx::query(indoc! {"
+                INSERT INTO channels (id, model, temperature, top_p, max_tokens, reasoning)
+                VALUES ($1, $2, $3, $4, $5, $6)
+                ON CONFLICT (id)
+                DO UPDATE SET
+                    model = $2,
+                    temperature = $3,
+                    top_p = $4,
+                    max_tokens = $5,
+                    reasoning = $6;
+            "})
+            .bind(id.get() as i64)
+            .bind(&overrides.model)
+            .bind(overrides.temperature)
+            .bind(overrides.top_p)
+            .bind(overrides.max_tokens)
+            .bind(&reasoning)
+            .execute(&mut **c)
+            .await?
+            .rows_affected()
+        });
+        Ok(())
+    }
+
+    /// Sets a channel's own system prompt, `None` makes it follow its parent or guild again
+    pub async fn set_system_prompt(
+        &mut self,
+        id: ChannelId,
+        system_prompt: Option<i64>,
+    ) -> eyre::Result<()> {
+        dispatch!(self, c => {
+            sqlx::query(indoc! {"
+                UPDATE channels
+                SET system_prompt = $1
+                WHERE id = $2;
+            "})
+            .bind(system_prompt)
+            .bind(id.get() as i64)
+            .execute(&mut **c)
+            .await?
+            .rows_affected()
+        });
+        Ok(())
+    }
+
+    pub async fn reset_context(&mut self, id: ChannelId) -> eyre::Result<()> {
+        match self {
+            Connection::Postgres(c) => sqlx::query(indoc! {"
+                    UPDATE channels
+                    SET context_window = extract(epoch FROM now())::bigint,
+                        recall_window = extract(epoch FROM now())::bigint,
+                        summary = NULL
+                    WHERE id = $1;
+                "})
+            .bind(id.get() as i64)
+            .execute(&mut **c)
+            .await?
+            .rows_affected(),
+            Connection::Sqlite(c) => sqlx::query(indoc! {"
+                    UPDATE channels
+                    SET context_window = unixepoch(),
+                        recall_window = unixepoch(),
+                        summary = NULL
+                    WHERE id = $1;
+                "})
+            .bind(id.get() as i64)
+            .execute(&mut **c)
+            .await?
+            .rows_affected(),
+        };
+        // Nothing from before the reset is recalled again, so its embeddings are no use
+        dispatch!(self, c => {
+            sqlx::query(indoc! {"
+                DELETE FROM embeddings
+                WHERE channel = $1;
+            "})
+            .bind(id.get() as i64)
+            .execute(&mut **c)
+            .await?
+            .rows_affected()
+        });
+        Ok(())
+    }
+
+    pub async fn set_context_window(&mut self, id: ChannelId, time: u64) -> eyre::Result<()> {
+        dispatch!(self, c => {
+            sqlx::query(indoc! {"
+                UPDATE channels
+                SET context_window = $2
+                WHERE id = $1;
+            "})
+            .bind(id.get() as i64)
+            .bind(time as i64)
+            .execute(&mut **c)
+            .await?
+            .rows_affected()
+        });
+        Ok(())
+    }
+
+    /// Moves the context window forward, replacing the summary of the messages before it
+    pub async fn summarize_context(
+        &mut self,
+        id: ChannelId,
+        time: u64,
+        summary: &str,
+    ) -> eyre::Result<()> {
+        dispatch!(self, c => {
+            sqlx::query(indoc! {"
+                UPDATE channels
+                SET context_window = $2,
+                    summary = $3
+                WHERE id = $1;
+            "})
+            .bind(id.get() as i64)
+            .bind(time as i64)
+            .bind(summary)
+            .execute(&mut **c)
+            .await?
+            .rows_affected()
+        });
+        Ok(())
+    }
+
+    pub async fn system_prompt(&mut self, id: i64) -> eyre::Result<SystemPrompt> {
+        Ok(dispatch!(self, c => {
+            sqlx::query_as(indoc! {"
+                SELECT *
+                FROM system_prompts
+                WHERE id = $1;
+            "})
+            .bind(id)
+            .fetch_one(&mut **c)
+            .await?
+        }))
+    }
+
+    pub async fn system_prompt_by_name(
+        &mut self,
+        name: &str,
+    ) -> eyre::Result<Option<SystemPrompt>> {
+        Ok(dispatch!(self, c => {
+            sqlx::query_as(indoc! {"
+                SELECT *
+                FROM system_prompts
+                WHERE name = $1;
+            "})
+            .bind(name)
+            .fetch_optional(&mut **c)
+            .await?
+        }))
+    }
+
+    pub async fn system_prompts(&mut self) -> eyre::Result<Vec<SystemPrompt>> {
+        Ok(dispatch!(self, c => {
+            sqlx::query_as(indoc! {"
+                SELECT *
+                FROM system_prompts
+                ORDER BY id ASC;
+            "})
+            .fetch_all(&mut **c)
+            .await?
+        }))
+    }
+
+    pub async fn create_system_prompt(&mut self, name: &str, contents: &str) -> eyre::Result<i64> {
+        Ok(dispatch!(self, c => {
+            sqlx::query_scalar(indoc! {"
+                INSERT INTO system_prompts (name, contents)
+                VALUES ($1, $2)
+                RETURNING id;
+            "})
+            .bind(name)
+            .bind(contents)
+            .fetch_one(&mut **c)
+            .await?
+        }))
+    }
+
+    pub async fn update_system_prompt(&mut self, id: i64, contents: &str) -> eyre::Result<()> {
+        dispatch!(self, c => {
+            sqlx::query(indoc! {"
+                UPDATE system_prompts
+                SET contents = $2
+                WHERE id = $1;
+            "})
+            .bind(id)
+            .bind(contents)
+            .execute(&mut **c)
+            .await?
+            .rows_affected()
+        });
+        Ok(())
+    }
+
+    /// Deletes a system prompt, channels and guilds that use it go back to what they inherit
+    pub async fn delete_system_prompt(&mut self, id: i64) -> eyre::Result<()> {
+        dispatch!(self, c => {
+            sqlx::query(indoc! {"
+                UPDATE channels
+                SET system_prompt = NULL
+                WHERE system_prompt = $1;
+            "})
+            .bind(id)
+            .execute(&mut **c)
+            .await?
+            .rows_affected()
+        });
+        dispatch!(self, c => {
+            sqlx::query(indoc! {"
+                UPDATE guilds
+                SET system_prompt = NULL
+                WHERE system_prompt = $1;
+            "})
+            .bind(id)
+            .execute(&mut **c)
+            .await?
+            .rows_affected()
+        });
+        dispatch!(self, c => {
+            sqlx::query(indoc! {"
+                DELETE FROM system_prompts
+                WHERE id = $1;
+            "})
+            .bind(id)
+            .execute(&mut **c)
+            .await?
+            .rows_affected()
+        });
+        Ok(())
+    }
+
+    pub async fn channel_system_prompt(
+        &mut self,
+        id: ChannelId,
+    ) -> eyre::Result<Option<SystemPrompt>> {
+        Ok(dispatch!(self, c => {
+            sqlx::query_as(indoc! {"
+                SELECT sp.*
+                FROM channels c
+                LEFT JOIN channels p ON p.id = c.parent
+                LEFT JOIN guilds g ON g.id = c.guild
+                JOIN system_prompts sp
+                    ON sp.id = COALESCE(c.system_prompt, p.system_prompt, g.system_prompt, $2)
+                WHERE c.id = $1;
+            "})
+            .bind(id.get() as i64)
+            .bind(DEFAULT_PROMPT_ID)
+            .fetch_optional(&mut **c)
+            .await?
+        }))
+    }
+
+    /// Inserts a message, or restores and updates it if it was already stored
+    pub async fn save_message(&mut self, message: &NewMessage) -> eyre::Result<()> {
+        dispatch!(self, c => {
+            sqlx::query(indoc! {"
+                INSERT INTO messages (
+                    id, is_self, mentions_self, sender, sender_name, sender_display_name, guild, channel, contents, reply
+                ) VALUES (
+                    $1, $2, $3, $4, $5, $6, $7, $8, $9, (SELECT id FROM messages WHERE id = $10)
+                )
+                ON CONFLICT (id)
+                DO UPDATE SET
+                    contents = $9,
+                    deleted = false;
+            "})
+            .bind(message.id.get() as i64)
+            .bind(message.is_self)
+            .bind(message.mentions_self)
+            .bind(message.sender.get() as i64)
+            .bind(&message.sender_name)
+            .bind(&message.sender_display_name)
+            .bind(message.guild.map(|id| id.get() as i64))
+            .bind(message.channel.get() as i64)
+            .bind(&message.contents)
+            .bind(message.reply.map(|id| id.get() as i64))
+            .execute(&mut **c)
+            .await?
+            .rows_affected()
+        });
+        Ok(())
+    }
+
+    /// Returns whether a stored message was updated
+    pub async fn update_message(
+        &mut self,
+        id: MessageId,
+        contents: &str,
+        mentions_self: bool,
+    ) -> eyre::Result<bool> {
+        let result = dispatch!(self, c => {
+            sqlx::query(indoc! {"
+                UPDATE messages
+                SET contents = $2,
+                    mentions_self = $3
+                WHERE id = $1
+                    AND deleted IS FALSE;
+            "})
+            .bind(id.get() as i64)
+            .bind(contents)
+            .bind(mentions_self)
+            .execute(&mut **c)
+            .await?
+            .rows_affected()
+        });
+        // The old embedding no longer matches, the message is embedded again when needed
+        self.delete_embedding(id).await?;
+        Ok(result > 0)
+    }
+
+    /// Tombstones a message, its row is kept so replies to it remain valid
+    pub async fn delete_message(&mut self, id: MessageId) -> eyre::Result<()> {
+        dispatch!(self, c => {
+            sqlx::query(indoc! {"
+                UPDATE messages
+                SET deleted = true,
+                    contents = ''
+                WHERE id = $1;
+            "})
+            .bind(id.get() as i64)
+            .execute(&mut **c)
+            .await?
+            .rows_affected()
+        });
+        self.delete_embedding(id).await?;
+        self.delete_attachments(id).await
+    }
+
+    /// Lumi's replies to a message, including every part of a reply split across messages
+    pub async fn reply_chain(&mut self, id: MessageId) -> eyre::Result<Vec<MessageId>> {
+        let ids: Vec<i64> = dispatch!(self, c => {
+            sqlx::query_scalar(indoc! {"
+                WITH RECURSIVE parts AS (
+                    SELECT id
+                    FROM messages
+                    WHERE reply = $1
+                        AND is_self IS TRUE
+                        AND deleted IS FALSE
+                    UNION ALL
+                    SELECT m.id
+                    FROM messages m
+                    JOIN parts p ON m.reply = p.id
+                    WHERE m.is_self IS TRUE
+                        AND m.deleted IS FALSE
+                )
+                SELECT id
+                FROM parts
+                ORDER BY id ASC;
+            "})
+            .bind(id.get() as i64)
+            .fetch_all(&mut **c)
+            .await?
+        });
+        Ok(ids
+            .into_iter()
+            .map(|id| MessageId::new(id as u64))
+            .collect())
+    }
+
+    pub async fn insert_attachment(&mut self, attachment: &Attachment) -> eyre::Result<()> {
+        dispatch!(self, c => {
+            sqlx::query(indoc! {"
+                INSERT INTO attachments (
+                    id, message, filename, url, content_type, description
+                ) VALUES (
+                    $1, $2, $3, $4, $5, $6
+                );
+            "})
+            .bind(attachment.id as i64)
+            .bind(attachment.message as i64)
+            .bind(&attachment.filename)
+            .bind(&attachment.url)
+            .bind(&attachment.content_type)
+            .bind(&attachment.description)
+            .execute(&mut **c)
+            .await?
+            .rows_affected()
+        });
+        Ok(())
+    }
+
+    pub async fn delete_attachments(&mut self, message: MessageId) -> eyre::Result<()> {
+        dispatch!(self, c => {
+            sqlx::query(indoc! {"
+                DELETE FROM attachments
+                WHERE message = $1;
+            "})
+            .bind(message.get() as i64)
+            .execute(&mut **c)
+            .await?
+            .rows_affected()
+        });
+        Ok(())
+    }
+
+    /// Messages in the channel's context window, optionally only those mentioning Lumi and only up
+    /// to a given message
+    pub async fn context_messages(
+        &mut self,
+        channel: ChannelId,
+        all_messages: bool,
+        until: Option<MessageId>,
+    ) -> eyre::Result<Vec<Message>> {
+        Ok(dispatch!(self, c => {
+            sqlx::query_as(indoc! {"
+                SELECT m.*,
+                    rm.sender_name AS reply_sender_name,
+                    rm.contents AS reply_contents
+                FROM messages m
+                JOIN channels c ON c.id = m.channel
+                LEFT JOIN messages rm ON rm.id = m.reply AND rm.deleted IS FALSE
+                WHERE c.id = $1
+                    AND m.time > c.context_window
+                    AND m.deleted IS FALSE
+                    AND (m.mentions_self IS TRUE OR $2 IS TRUE)
+                    AND ($3 IS NULL OR m.id <= $3)
+                ORDER BY m.id ASC;
+            "})
+            .bind(channel.get() as i64)
+            .bind(all_messages)
+            .bind(until.map(|id| id.get() as i64))
+            .fetch_all(&mut **c)
+            .await?
+        }))
+    }
+
+    /// The parent channel's messages a thread starts from, its starter message and up to `limit`
+    /// messages before it
+    pub async fn thread_seed(
+        &mut self,
+        parent: ChannelId,
+        thread: ChannelId,
+        limit: usize,
+        all_messages: bool,
+    ) -> eyre::Result<Vec<Message>> {
+        Ok(dispatch!(self, c => {
+            sqlx::query_as(indoc! {"
+                SELECT m.*,
+                    rm.sender_name AS reply_sender_name,
+                    rm.contents AS reply_contents
+                FROM messages m
+                LEFT JOIN messages rm ON rm.id = m.reply AND rm.deleted IS FALSE
+                WHERE m.channel = $1
+                    AND m.deleted IS FALSE
+                    AND (
+                        m.id = $2
+                        OR m.id IN (
+                            SELECT id
+                            FROM messages
+                            WHERE channel = $1
+                                AND id < $2
+                                AND deleted IS FALSE
+                                AND (mentions_self IS TRUE OR $4 IS TRUE)
+                            ORDER BY id DESC
+                            LIMIT $3
+                        )
+                    )
+                ORDER BY m.id ASC;
+            "})
+            .bind(parent.get() as i64)
+            .bind(thread.get() as i64)
+            .bind(limit as i64)
+            .bind(all_messages)
+            .fetch_all(&mut **c)
+            .await?
+        }))
+    }
+
+    pub async fn context_attachments(
+        &mut self,
+        channel: ChannelId,
+    ) -> eyre::Result<Vec<Attachment>> {
+        Ok(dispatch!(self, c => {
+            sqlx::query_as(indoc! {"
+                SELECT a.*
+                FROM attachments a
+                JOIN messages m ON m.id = a.message
+                JOIN channels c ON c.id = m.channel
+                WHERE c.id = $1
+                    AND m.time > c.context_window
+                    AND m.deleted IS FALSE
+                ORDER BY a.id ASC;
+            "})
+            .bind(channel.get() as i64)
+            .fetch_all(&mut **c)
+            .await?
+        }))
+    }
+
+    /// Roles allowed to configure Lumi in a guild, an empty list leaves it to Discord's permissions
+    pub async fn command_roles(&mut self, guild: GuildId) -> eyre::Result<Vec<RoleId>> {
+        let roles: Vec<i64> = dispatch!(self, c => {
+            sqlx::query_scalar(indoc! {"
+                SELECT role
+                FROM command_roles
+                WHERE guild = $1
+                ORDER BY role ASC;
+            "})
+            .bind(guild.get() as i64)
+            .fetch_all(&mut **c)
+            .await?
+        });
+        Ok(roles
+            .into_iter()
+            .map(|role| RoleId::new(role as u64))
+            .collect())
+    }
+
+    pub async fn allow_command_role(&mut self, guild: GuildId, role: RoleId) -> eyre::Result<()> {
+        dispatch!(self, c => {
+            sqlx::query(indoc! {"
+                INSERT INTO command_roles (guild, role)
+                VALUES ($1, $2)
+                ON CONFLICT (guild, role) DO NOTHING;
+            "})
+            .bind(guild.get() as i64)
+            .bind(role.get() as i64)
+            .execute(&mut **c)
+            .await?
+            .rows_affected()
+        });
+        Ok(())
+    }
+
+    pub async fn deny_command_role(&mut self, guild: GuildId, role: RoleId) -> eyre::Result<()> {
+        dispatch!(self, c => {
+            sqlx::query(indoc! {"
+                DELETE FROM command_roles
+                WHERE guild = $1
+                    AND role = $2;
+            "})
+            .bind(guild.get() as i64)
+            .bind(role.get() as i64)
+            .execute(&mut **c)
+            .await?
+            .rows_affected()
+        });
+        Ok(())
+    }
+
+    /// Records the reply decision for a message, replacing an earlier one
+    pub async fn save_decision(&mut self, decision: &NewDecision) -> eyre::Result<()> {
+        dispatch!(self, c => {
+            sqlx::query(indoc! {"
+                INSERT INTO decisions (
+                    message, channel, model, should_reply, confidence, reason, latency
+                ) VALUES (
+                    $1, $2, $3, $4, $5, $6, $7
+                )
+                ON CONFLICT (message)
+                DO UPDATE SET
+                    model = $3,
+                    should_reply = $4,
+                    confidence = $5,
+                    reason = $6,
+                    latency = $7;
+            "})
+            .bind(decision.message.get() as i64)
+            .bind(decision.channel.get() as i64)
+            .bind(&decision.model)
+            .bind(decision.should_reply)
+            .bind(decision.confidence)
+            .bind(&decision.reason)
+            .bind(decision.latency.map(|latency| latency.as_millis() as i64))
+            .execute(&mut **c)
+            .await?
+            .rows_affected()
+        });
+        Ok(())
+    }
+
+    pub async fn decision(&mut self, message: MessageId) -> eyre::Result<Option<Decision>> {
+        Ok(dispatch!(self, c => {
+            sqlx::query_as(indoc! {"
+                SELECT *
+                FROM decisions
+                WHERE message = $1;
+            "})
+            .bind(message.get() as i64)
+            .fetch_optional(&mut **c)
+            .await?
+        }))
+    }
+
+    pub async fn record_usage(&mut self, usage: &NewUsage) -> eyre::Result<()> {
+        dispatch!(self, c => {
+            sqlx::query(indoc! {"
+                INSERT INTO usage (
+                    guild, channel, sender, model, prompt_tokens, completion_tokens, kind
+                ) VALUES (
+                    $1, $2, $3, $4, $5, $6, $7
+                );
+            "})
+            .bind(usage.guild.map(|guild| guild.get() as i64))
+            .bind(usage.channel.get() as i64)
+            .bind(usage.sender.map(|sender| sender.get() as i64))
+            .bind(&usage.model)
+            .bind(usage.prompt_tokens as i64)
+            .bind(usage.completion_tokens as i64)
+            .bind(usage.kind.as_str())
+            .execute(&mut **c)
+            .await?
+            .rows_affected()
+        });
+        Ok(())
+    }
+
+    /// Sums up usage per model, most expensive in tokens first
+    pub async fn usage_totals(&mut self, filter: &UsageFilter) -> eyre::Result<Vec<UsageTotal>> {
+        Ok(dispatch!(self, c => {
+            sqlx::query_as(indoc! {"
+                SELECT
+                    model,
+                    COUNT(*) AS requests,
+                    CAST(SUM(prompt_tokens) AS BIGINT) AS prompt_tokens,
+                    CAST(SUM(completion_tokens) AS BIGINT) AS completion_tokens
+                FROM usage
+                WHERE time >= $1
+                    AND ($2 IS NULL OR guild = $2)
+                    AND ($3 IS NULL OR channel = $3)
+                    AND ($4 IS NULL OR sender = $4)
+                    AND ($5 IS FALSE OR kind IN ('chat', 'social'))
+                GROUP BY model
+                ORDER BY SUM(prompt_tokens + completion_tokens) DESC;
+            "})
+            .bind(filter.since as i64)
+            .bind(filter.guild.map(|guild| guild.get() as i64))
+            .bind(filter.channel.map(|channel| channel.get() as i64))
+            .bind(filter.sender.map(|sender| sender.get() as i64))
+            .bind(filter.completions_only)
+            .fetch_all(&mut **c)
+            .await?
+        }))
+    }
+
+    /// Everything Lumi remembers about a user in a guild, oldest first
+    pub async fn memories(
+        &mut self,
+        subject: UserId,
+        guild: Option<GuildId>,
+    ) -> eyre::Result<Vec<Memory>> {
+        Ok(dispatch!(self, c => {
+            sqlx::query_as(indoc! {"
+                SELECT *
+                FROM memories
+                WHERE subject = $1
+                    AND guild IS NOT DISTINCT FROM $2
+                ORDER BY id ASC;
+            "})
+            .bind(subject.get() as i64)
+            .bind(guild.map(|guild| guild.get() as i64))
+            .fetch_all(&mut **c)
+            .await?
+        }))
+    }
+
+    /// Stores a memory, failing once the user already has the most memories allowed
+    pub async fn remember(
+        &mut self,
+        subject: UserId,
+        guild: Option<GuildId>,
+        contents: &str,
+    ) -> eyre::Result<i64> {
+        let count: i64 = dispatch!(self, c => {
+            sqlx::query_scalar(indoc! {"
+                SELECT COUNT(*)
+                FROM memories
+                WHERE subject = $1
+                    AND guild IS NOT DISTINCT FROM $2;
+            "})
+            .bind(subject.get() as i64)
+            .bind(guild.map(|guild| guild.get() as i64))
+            .fetch_one(&mut **c)
+            .await?
+        });
+        if count as u64 >= MAX_MEMORIES {
+            eyre::bail!("Lumi already remembers {MAX_MEMORIES} things about this user");
+        }
+        Ok(dispatch!(self, c => {
+            sqlx::query_scalar(indoc! {"
+                INSERT INTO memories (subject, guild, contents)
+                VALUES ($1, $2, $3)
+                RETURNING id;
+            "})
+            .bind(subject.get() as i64)
+            .bind(guild.map(|guild| guild.get() as i64))
+            .bind(contents)
+            .fetch_one(&mut **c)
+            .await?
+        }))
+    }
+
+    /// Forgets one of a user's memories, or all of them when `id` is `None`. Returns how many were
+    /// forgotten
+    pub async fn forget(
+        &mut self,
+        subject: UserId,
+        guild: Option<GuildId>,
+        id: Option<i64>,
+    ) -> eyre::Result<u64> {
+        Ok(dispatch!(self, c => {
+            sqlx::query(indoc! {"
+                DELETE FROM memories
+                WHERE subject = $1
+                    AND guild IS NOT DISTINCT FROM $2
+                    AND ($3 IS NULL OR id = $3);
+            "})
+            .bind(subject.get() as i64)
+            .bind(guild.map(|guild| guild.get() as i64))
+            .bind(id)
+            .execute(&mut **c)
+            .await?
+            .rows_affected()
+        }))
+    }
+
+    /// Messages before `before`, but after the channel was last reset, that have no embedding
+    /// from the model yet, newest first
+    pub async fn missing_embeddings(
+        &mut self,
+        channel: ChannelId,
+        before: MessageId,
+        model: &str,
+        all_messages: bool,
+        limit: usize,
+    ) -> eyre::Result<Vec<Message>> {
+        Ok(dispatch!(self, c => {
+            sqlx::query_as(indoc! {"
+                SELECT m.*,
+                    NULL AS reply_sender_name,
+                    NULL AS reply_contents
+                FROM messages m
+                JOIN channels c ON c.id = m.channel
+                LEFT JOIN embeddings e ON e.message = m.id AND e.model = $3
+                WHERE m.channel = $1
+                    AND m.id < $2
+                    AND m.time > c.recall_window
+                    AND m.deleted IS FALSE
+                    AND m.contents <> ''
+                    AND (m.mentions_self IS TRUE OR $4 IS TRUE)
+                    AND e.message IS NULL
+                ORDER BY m.id DESC
+                LIMIT $5;
+            "})
+            .bind(channel.get() as i64)
+            .bind(before.get() as i64)
+            .bind(model)
+            .bind(all_messages)
+            .bind(limit as i64)
+            .fetch_all(&mut **c)
+            .await?
+        }))
+    }
+
+    /// Stores a message's embedding, which has to be normalized
+    pub async fn save_embedding(
+        &mut self,
+        message: MessageId,
+        channel: ChannelId,
+        model: &str,
+        embedding: &[f32],
+    ) -> eyre::Result<()> {
+        let query = indoc! {"
+            INSERT INTO embeddings (message, channel, model, embedding)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (message)
+            DO UPDATE SET
+                model = $3,
+                embedding = $4;
+        "};
+        match self {
+            Connection::Postgres(c) => sqlx::query(query)
+                .bind(message.get() as i64)
+                .bind(channel.get() as i64)
+                .bind(model)
+                .bind(embedding)
+                .execute(&mut **c)
+                .await?
+                .rows_affected(),
+            Connection::Sqlite(c) => sqlx::query(query)
+                .bind(message.get() as i64)
+                .bind(channel.get() as i64)
+                .bind(model)
+                .bind(
+                    embedding
+                        .iter()
+                        .flat_map(|value| value.to_le_bytes())
+                        .collect::<Vec<_>>(),
+                )
+                .execute(&mut **c)
+                .await?
+                .rows_affected(),
+        };
+        Ok(())
+    }
+
+    pub async fn delete_embedding(&mut self, message: MessageId) -> eyre::Result<()> {
+        dispatch!(self, c => {
+            sqlx::query(indoc! {"
+                DELETE FROM embeddings
+                WHERE message = $1;
+            "})
+            .bind(message.get() as i64)
+            .execute(&mut **c)
+            .await?
+            .rows_affected()
+        });
+        Ok(())
+    }
+
+    /// The messages before `before`, but after the channel was last reset, most similar to a
+    /// normalized embedding, oldest first.
+    /// Postgres ranks them itself, SQLite has no array type so they are ranked here
+    #[allow(clippy::too_many_arguments)]
+    pub async fn similar_messages(
+        &mut self,
+        channel: ChannelId,
+        before: MessageId,
+        model: &str,
+        embedding: &[f32],
+        all_messages: bool,
+        limit: usize,
+        min_similarity: f32,
+    ) -> eyre::Result<Vec<Message>> {
+        let mut messages: Vec<Message> = match self {
+            Connection::Postgres(c) => {
+                sqlx::query_as(indoc! {"
+                    SELECT m.*,
+                        rm.sender_name AS reply_sender_name,
+                        rm.contents AS reply_contents
+                    FROM (
+                        SELECT
+                            e.message,
+                            (
+                                SELECT SUM(a * b)
+                                FROM unnest(e.embedding, $4::REAL[]) AS v(a, b)
+                            ) AS similarity
+                        FROM embeddings e
+                        WHERE e.channel = $1
+                            AND e.message < $2
+                            AND e.model = $3
+                    ) s
+                    JOIN messages m ON m.id = s.message
+                    JOIN channels c ON c.id = m.channel
+                    LEFT JOIN messages rm ON rm.id = m.reply AND rm.deleted IS FALSE
+                    WHERE m.deleted IS FALSE
+                        AND m.time > c.recall_window
+                        AND (m.mentions_self IS TRUE OR $5 IS TRUE)
+                        AND s.similarity >= $7
+                    ORDER BY s.similarity DESC
+                    LIMIT $6;
+                "})
+                .bind(channel.get() as i64)
+                .bind(before.get() as i64)
+                .bind(model)
+                .bind(embedding)
+                .bind(all_messages)
+                .bind(limit as i64)
+                .bind(min_similarity)
+                .fetch_all(&mut **c)
+                .await?
+            }
+            Connection::Sqlite(c) => {
+                let candidates: Vec<(i64, Vec<u8>)> = sqlx::query_as(indoc! {"
+                    SELECT e.message, e.embedding
+                    FROM embeddings e
+                    JOIN messages m ON m.id = e.message
+                    JOIN channels c ON c.id = m.channel
+                    WHERE e.channel = $1
+                        AND e.message < $2
+                        AND e.model = $3
+                        AND m.deleted IS FALSE
+                        AND m.time > c.recall_window
+                        AND (m.mentions_self IS TRUE OR $4 IS TRUE);
+                "})
+                .bind(channel.get() as i64)
+                .bind(before.get() as i64)
+                .bind(model)
+                .bind(all_messages)
+                .fetch_all(&mut **c)
+                .await?;
+                let mut ranked = candidates
+                    .into_iter()
+                    .map(|(id, candidate)| {
+                        let similarity = candidate
+                            .chunks_exact(4)
+                            .zip(embedding)
+                            .map(|(bytes, value)| {
+                                f32::from_le_bytes(bytes.try_into().unwrap()) * value
+                            })
+                            .sum::<f32>();
+                        (id, similarity)
+                    })
+                    .filter(|(_, similarity)| *similarity >= min_similarity)
+                    .collect::<Vec<_>>();
+                ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+                ranked.truncate(limit);
+                let ids = ranked.into_iter().map(|(id, _)| id).collect::<Vec<_>>();
+                sqlx::query_as(indoc! {"
+                    SELECT m.*,
+                        rm.sender_name AS reply_sender_name,
+                        rm.contents AS reply_contents
+                    FROM messages m
+                    LEFT JOIN messages rm ON rm.id = m.reply AND rm.deleted IS FALSE
+                    WHERE m.id IN (SELECT value FROM json_each($1));
+                "})
+                .bind(serde_json::to_string(&ids)?)
+                .fetch_all(&mut **c)
+                .await?
+            }
+        };
+        messages.sort_by_key(|message| message.id);
+        Ok(messages)
+    }
+}
@@ -1,15 +1,26 @@
-use indoc::indoc;
-use openai_api_rs::v1::api::OpenAIClient;
+use std::time::Duration;
+
 use serenity::{all::Message as SerenityMessage, all::*, async_trait};
-use sqlx::PgPool;
-use tokio::sync::{Mutex, RwLock};
+use tokio::sync::RwLock;
 
-use crate::{Config, chat::chatbot, commands, db};
+use crate::{
+    Config,
+    chat::{
+        chatbot, client::LlmClient, debounce::Debouncer, limits::RateLimiter, locks::ChannelLocks,
+        tokens, tools,
+    },
+    commands, db,
+};
 
 pub struct Handler {
     pub config: RwLock<Config>,
-    pub openai: Mutex<OpenAIClient>,
-    pub db: PgPool,
+    pub openai: LlmClient,
+    pub tools: tools::Registry,
+    pub estimator: Box<dyn tokens::Estimator>,
+    pub channel_locks: ChannelLocks,
+    pub debouncer: Debouncer,
+    pub user_limiter: RateLimiter<UserId>,
+    pub db: db::Database,
 }
 
 #[async_trait]
@@ -31,6 +42,178 @@ impl EventHandler for Handler {
         }
     }
 
+    async fn message_update(
+        &self,
+        ctx: Context,
+        _old_if_available: Option<SerenityMessage>,
+        new: Option<SerenityMessage>,
+        event: MessageUpdateEvent,
+    ) {
+        // Embed resolution also triggers updates, only content changes matter here
+        if event.content.is_none() || event.author.as_ref().is_some_and(|author| author.bot) {
+            return;
+        }
+        let msg = match new {
+            Some(msg) => msg,
+            None => match event.channel_id.message(&ctx, event.id).await {
+                Ok(msg) => msg,
+                Err(err) => {
+                    println!("Error fetching message: {err:?}");
+                    return;
+                }
+            },
+        };
+        if msg.author.bot {
+            return;
+        }
+
+        #[allow(deprecated)]
+        let is_private = msg.is_private();
+        let mentions_me = is_private || msg.mentions_me(&ctx).await.unwrap_or(false);
+
+        let mut transaction = self
+            .db
+            .begin()
+            .await
+            .expect("Failed to acquire transaction");
+
+        let Some(channel) = transaction
+            .conn()
+            .channel(msg.channel_id)
+            .await
+            .expect("Failed to read channels table")
+        else {
+            return;
+        };
+
+        let updated = transaction
+            .conn()
+            .update_message(msg.id, &msg.content_safe(&ctx), mentions_me)
+            .await
+            .expect("Failed to update message in database");
+        if !updated {
+            return;
+        }
+
+        transaction
+            .conn()
+            .delete_attachments(msg.id)
+            .await
+            .expect("Failed to remove attachments from database");
+        for attachment in &msg.attachments {
+            transaction
+                .conn()
+                .insert_attachment(&db::Attachment::new(msg.id, attachment))
+                .await
+                .expect("Failed to add attachment to database");
+        }
+        transaction
+            .commit()
+            .await
+            .expect("Failed to commit transaction");
+
+        if self.config.read().await.discord.regenerate_on_edit {
+            let _lock = self.channel_locks.lock(msg.channel_id).await;
+            let previous = self
+                .db
+                .acquire()
+                .await
+                .expect("Failed to acquire connection")
+                .conn()
+                .reply_chain(msg.id)
+                .await
+                .expect("Failed to read messages table");
+
+            let mut replies = vec![];
+            for id in previous {
+                match msg.channel_id.message(&ctx, id).await {
+                    Ok(reply) => replies.push(reply),
+                    Err(err) => println!("Error fetching message: {err:?}"),
+                }
+            }
+
+            if replies.is_empty() {
+                return;
+            }
+            // Edits would otherwise be a way around the limits
+            match self.check_limits(&msg).await {
+                Ok(None) => {}
+                Ok(Some(notice)) => {
+                    send_notice(&ctx, &msg, notice).await;
+                    return;
+                }
+                Err(err) => {
+                    println!("Error checking limits: {err:?}");
+                    return;
+                }
+            }
+            if let Err(err) = chatbot::regenerate(
+                &self.db,
+                &self.openai,
+                &self.tools,
+                self.estimator.as_ref(),
+                &self.config,
+                &msg,
+                &ctx,
+                channel.chat_mode,
+                replies,
+            )
+            .await
+            {
+                println!("Error regenerating reply: {err:?}");
+            }
+        }
+    }
+
+    async fn message_delete(
+        &self,
+        _ctx: Context,
+        _channel_id: ChannelId,
+        deleted_message_id: MessageId,
+        _guild_id: Option<GuildId>,
+    ) {
+        delete_messages(&self.db, &[deleted_message_id])
+            .await
+            .expect("Failed to delete message from database");
+    }
+
+    async fn message_delete_bulk(
+        &self,
+        _ctx: Context,
+        _channel_id: ChannelId,
+        multiple_deleted_messages_ids: Vec<MessageId>,
+        _guild_id: Option<GuildId>,
+    ) {
+        delete_messages(&self.db, &multiple_deleted_messages_ids)
+            .await
+            .expect("Failed to delete messages from database");
+    }
+
+    async fn thread_create(&self, _ctx: Context, thread: GuildChannel) {
+        let Some(parent) = thread.parent_id else {
+            return;
+        };
+        let mut transaction = self
+            .db
+            .begin()
+            .await
+            .expect("Failed to acquire transaction");
+        transaction
+            .conn()
+            .ensure_channel(parent, Some(thread.guild_id))
+            .await
+            .expect("Failed to add channel to database");
+        transaction
+            .conn()
+            .ensure_thread(thread.id, parent)
+            .await
+            .expect("Failed to add thread to database");
+        transaction
+            .commit()
+            .await
+            .expect("Failed to commit transaction");
+    }
+
     async fn ready(&self, ctx: Context, ready: Ready) {
         Command::set_global_commands(
             &ctx,
@@ -39,6 +222,13 @@ impl EventHandler for Handler {
                 commands::reset_context::register(),
                 commands::system_prompt::register(),
                 commands::chat_mode::register(),
+                commands::permissions::register(),
+                commands::model::register(),
+                commands::server_settings::register(),
+                commands::usage::register(),
+                commands::why::register(),
+                commands::remember::register(),
+                commands::memory::register(),
             ],
         )
         .await
@@ -48,20 +238,25 @@ impl EventHandler for Handler {
 
     async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
         if let Interaction::Command(command) = interaction {
-            let res = match command.data.name.as_str() {
-                "reload" => commands::reload::run(&ctx, &command, &self).await,
-                "reset_context" => commands::reset_context::run(&ctx, &command, &self).await,
-                "system_prompt" => commands::system_prompt::run(&ctx, &command, &self).await,
-                "chat_mode" => commands::chat_mode::run(&ctx, &command, &self).await,
-                _ => {
+            let res = match commands::permissions::authorize(&command, self).await {
+                Ok(true) => self.dispatch(&ctx, &command).await,
+                Ok(false) => {
                     let response: CreateInteractionResponse = CreateInteractionResponse::Message(
-                        CreateInteractionResponseMessage::new().content("Unknown command :("),
+                        CreateInteractionResponseMessage::new()
+                            .embed(
+                                CreateEmbed::new()
+                                    .title("Missing permissions")
+                                    .description("You aren't allowed to use this command here")
+                                    .color(15409955),
+                            )
+                            .ephemeral(true),
                     );
                     command
                         .create_response(&ctx.http, response)
                         .await
                         .map_err(Into::into)
                 }
+                Err(err) => Err(err),
             };
             if let Err(err1) = res {
                 let response: CreateInteractionResponse = CreateInteractionResponse::Message(
@@ -78,6 +273,37 @@ impl EventHandler for Handler {
                     println!("Fatal error: {err1:?} {err2:?}");
                 }
             }
+        } else if let Interaction::Autocomplete(autocomplete) = interaction {
+            let res = match autocomplete.data.name.as_str() {
+                "model" | "server_settings" => {
+                    commands::model::autocomplete(&ctx, &autocomplete, self).await
+                }
+                _ => Err(eyre::eyre!("Unknown autocomplete")),
+            };
+            if let Err(err) = res {
+                println!("Error responding to autocomplete: {err:?}");
+            }
+        } else if let Interaction::Modal(modal) = interaction {
+            let res = if commands::system_prompt::handles(&modal) {
+                commands::system_prompt::submit(&ctx, &modal, self).await
+            } else {
+                Err(eyre::eyre!("Unknown modal"))
+            };
+            if let Err(err1) = res {
+                let response: CreateInteractionResponse = CreateInteractionResponse::Message(
+                    CreateInteractionResponseMessage::new()
+                        .embed(
+                            CreateEmbed::new()
+                                .title("Encountered an error in modal handler")
+                                .description(format!("```\n{err1:?}\n```"))
+                                .color(15409955),
+                        )
+                        .ephemeral(true),
+                );
+                if let Err(err2) = modal.create_response(&ctx.http, response).await {
+                    println!("Fatal error: {err1:?} {err2:?}");
+                }
+            }
         }
     }
 
@@ -86,7 +312,14 @@ impl EventHandler for Handler {
             return;
         }
 
-        if msg.content == "https://tenor.com/view/no-witnesses-erase-memory-forget-gif-20806865" {
+        if msg.content == "https://tenor.com/view/no-witnesses-erase-memory-forget-gif-20806865"
+            && commands::permissions::authorize_reset(&ctx, &msg, self)
+                .await
+                .unwrap_or_else(|err| {
+                    println!("Error checking permissions: {err:?}");
+                    false
+                })
+        {
             commands::reset_context::reset_context(&msg.channel_id, &self.db)
                 .await
                 .expect("Failed to reset context");
@@ -108,17 +341,21 @@ impl EventHandler for Handler {
         #[allow(deprecated)]
         let is_private = msg.is_private();
         let mentions_me = is_private || msg.mentions_me(&ctx).await.unwrap_or(false);
-        let chat_mode = sqlx::query_as::<_, db::Channel>(indoc! {"
-            SELECT *
-            FROM channels
-            WHERE id = $1;
-        "})
-        .bind(msg.channel_id.get() as i64)
-        .fetch_optional(&self.db)
-        .await
-        .expect("Failed to read channels table")
-        .map(|c| c.chat_mode)
-        .unwrap_or(db::ChatMode::MentionsOnlyAllContext);
+        // Threads created while Lumi wasn't watching are picked up by their first message
+        let known = self
+            .db
+            .acquire()
+            .await
+            .expect("Failed to acquire connection")
+            .conn()
+            .channel(msg.channel_id)
+            .await
+            .expect("Failed to read channels table")
+            .is_some();
+        let parent = match (known, msg.guild_id) {
+            (false, Some(_)) => thread_parent(&ctx, msg.channel_id).await,
+            _ => None,
+        };
 
         let mut transaction = self
             .db
@@ -126,74 +363,344 @@ impl EventHandler for Handler {
             .await
             .expect("Failed to acquire transaction");
 
-        sqlx::query(indoc! {"
-                WITH ensured_channel AS (
-                    INSERT INTO channels (id)
-                    VALUES ($1)
-                    ON CONFLICT (id) DO NOTHING
-                    RETURNING id
-                ),
-                locked AS (
-                    SELECT id
-                    FROM channels
-                    WHERE id = $1
-                    FOR UPDATE
-                )
-                INSERT INTO messages (
-                    id, is_self, mentions_self, sender, sender_name, sender_display_name, guild, channel, contents, reply
-                ) VALUES (
-                    $2, false, $3, $4, $5, $6, $7, $1, $8, (SELECT id FROM messages WHERE id = $9)
-                );
-            "})
-            .bind(msg.channel_id.get() as i64)
-            .bind(msg.id.get() as i64)
-            .bind(mentions_me)
-            .bind(msg.author.id.get() as i64)
-            .bind(&msg.author.name)
-            .bind(msg.author.display_name())
-            .bind(msg.guild_id.map(|id| id.get() as i64))
-            .bind(msg.content_safe(&ctx))
-            .bind(msg.referenced_message.clone().map(|m| m.id.get() as i64))
-            .execute(&mut *transaction)
+        if let Some(parent) = parent {
+            transaction
+                .conn()
+                .ensure_channel(parent, msg.guild_id)
+                .await
+                .expect("Failed to add channel to database");
+            transaction
+                .conn()
+                .ensure_thread(msg.channel_id, parent)
+                .await
+                .expect("Failed to add thread to database");
+        }
+        transaction
+            .conn()
+            .ensure_channel(msg.channel_id, msg.guild_id)
+            .await
+            .expect("Failed to add channel to database");
+        // Reading the channel resolves its chat mode through its parent and guild
+        let chat_mode = transaction
+            .conn()
+            .channel(msg.channel_id)
+            .await
+            .expect("Failed to read channels table")
+            .map(|c| c.chat_mode)
+            .unwrap_or(db::ChatMode::MentionsOnlyAllContext);
+        transaction
+            .conn()
+            .save_message(&db::NewMessage {
+                id: msg.id,
+                is_self: false,
+                mentions_self: mentions_me,
+                sender: msg.author.id,
+                sender_name: msg.author.name.to_owned(),
+                sender_display_name: msg.author.display_name().to_owned(),
+                guild: msg.guild_id,
+                channel: msg.channel_id,
+                contents: msg.content_safe(&ctx),
+                reply: msg.referenced_message.as_ref().map(|m| m.id),
+            })
             .await
             .expect("Failed to add message to database");
 
-        if mentions_me || chat_mode == db::ChatMode::FreeResponse {
-            if let Err(err) = chatbot::generate(
-                &mut transaction,
-                &self.openai,
-                &self.config,
-                &msg.channel_id,
-                &msg,
-                &ctx,
-                mentions_me,
-                chat_mode,
-            )
+        for attachment in &msg.attachments {
+            transaction
+                .conn()
+                .insert_attachment(&db::Attachment::new(msg.id, attachment))
+                .await
+                .expect("Failed to add attachment to database");
+        }
+        transaction
+            .commit()
             .await
+            .expect("Failed to commit transaction");
+
+        if !mentions_me && chat_mode != db::ChatMode::FreeResponse {
+            return;
+        }
+        // In free response, wait for the conversation to pause and reply to the burst as a whole
+        let debounce_interval = self.config.read().await.discord.debounce_interval;
+        let mentions_me = if chat_mode == db::ChatMode::FreeResponse && debounce_interval > 0 {
+            match self
+                .debouncer
+                .settle(
+                    msg.channel_id,
+                    msg.id,
+                    mentions_me,
+                    Duration::from_millis(debounce_interval),
+                )
+                .await
             {
-                if let Err(err2) = msg
-                    .channel_id
-                    .send_message(
-                        &ctx,
-                        CreateMessage::new()
-                            .embed(
-                                CreateEmbed::new()
-                                    .title("Encountered an error")
-                                    .description(format!("```\n{err:?}\n```"))
-                                    .color(15409955),
-                            )
-                            .allowed_mentions(CreateAllowedMentions::new()),
-                    )
-                    .await
-                {
-                    println!("Fatal error: {err:?} {err2:?}");
+                Some(mentions_me) => mentions_me,
+                None => return,
+            }
+        } else {
+            mentions_me
+        };
+        match self.check_limits(&msg).await {
+            Ok(None) => {}
+            Ok(Some(notice)) => {
+                // Staying silent is friendlier when Lumi wasn't addressed directly
+                if mentions_me {
+                    send_notice(&ctx, &msg, notice).await;
+                }
+                return;
+            }
+            Err(err) => {
+                println!("Error checking limits: {err:?}");
+                return;
+            }
+        }
+        // A mention in a continue in thread channel moves the conversation into a new thread
+        let msg =
+            if chat_mode == db::ChatMode::ContinueInThread && mentions_me && msg.guild_id.is_some()
+            {
+                match self.open_thread(&ctx, &msg).await {
+                    Ok(thread_msg) => thread_msg,
+                    Err(err) => {
+                        println!("Error opening thread: {err:?}");
+                        send_notice(&ctx, &msg, "Lumi couldn't open a thread here".into()).await;
+                        return;
+                    }
                 }
+            } else {
+                msg
             };
+        let _lock = self.channel_locks.lock(msg.channel_id).await;
+        if let Err(err) = chatbot::generate(
+            &self.db,
+            &self.openai,
+            &self.tools,
+            self.estimator.as_ref(),
+            &self.config,
+            &msg.channel_id,
+            &msg,
+            &ctx,
+            mentions_me,
+            chat_mode,
+        )
+        .await
+            && let Err(err2) = msg
+                .channel_id
+                .send_message(
+                    &ctx,
+                    CreateMessage::new()
+                        .embed(
+                            CreateEmbed::new()
+                                .title("Encountered an error")
+                                .description(format!("```\n{err:?}\n```"))
+                                .color(15409955),
+                        )
+                        .allowed_mentions(CreateAllowedMentions::new()),
+                )
+                .await
+        {
+            println!("Fatal error: {err:?} {err2:?}");
         }
+    }
+}
 
-        transaction
-            .commit()
-            .await
-            .expect("Failed to commit transaction");
+impl Handler {
+    /// Returns a notice for the user if replying to the message would exceed a rate limit or quota
+    async fn check_limits(&self, msg: &SerenityMessage) -> eyre::Result<Option<String>> {
+        let config = self.config.read().await;
+        if config.discord.owners.contains(&msg.author.id.get()) {
+            return Ok(None);
+        }
+        let mut conn = self.db.acquire().await?;
+        let guild = match msg.guild_id {
+            Some(guild) => conn.conn().guild(guild).await?,
+            None => None,
+        };
+        let limits = config.limits.for_guild(guild.as_ref());
+        let now = Timestamp::now().unix_timestamp().max(0) as u64;
+
+        if let Some(limit) = limits.channel_completions_per_hour {
+            let requests = conn
+                .conn()
+                .usage_totals(&db::UsageFilter {
+                    guild: None,
+                    channel: Some(msg.channel_id),
+                    sender: None,
+                    since: now.saturating_sub(3600),
+                    completions_only: true,
+                })
+                .await?
+                .iter()
+                .map(|total| total.requests)
+                .sum::<u64>();
+            if requests >= limit {
+                return Ok(Some(
+                    "Lumi has talked a lot in this channel lately, try again in a while".into(),
+                ));
+            }
+        }
+
+        if let Some(guild) = msg.guild_id
+            && (limits.guild_daily_tokens.is_some() || limits.guild_daily_cost.is_some())
+        {
+            let totals = conn
+                .conn()
+                .usage_totals(&db::UsageFilter {
+                    guild: Some(guild),
+                    channel: None,
+                    sender: None,
+                    since: now.saturating_sub(86400),
+                    completions_only: false,
+                })
+                .await?;
+            let tokens = totals
+                .iter()
+                .map(|total| total.prompt_tokens + total.completion_tokens)
+                .sum::<u64>();
+            let cost = totals
+                .iter()
+                .filter_map(|total| config.openrouter.cost(total))
+                .sum::<f64>();
+            if limits
+                .guild_daily_tokens
+                .is_some_and(|limit| tokens >= limit)
+                || limits.guild_daily_cost.is_some_and(|limit| cost >= limit)
+            {
+                return Ok(Some(
+                    "Lumi has reached this server's daily quota, try again tomorrow".into(),
+                ));
+            }
+        }
+
+        // Checked last, so messages turned away by the quotas above don't use up the user's budget
+        if let Some(limit) = limits.user_messages_per_minute
+            && let Err(wait) = self
+                .user_limiter
+                .hit(msg.author.id, limit, Duration::from_secs(60))
+        {
+            return Ok(Some(format!(
+                "You're messaging Lumi too quickly, try again in {} seconds",
+                wait.as_secs() + 1
+            )));
+        }
+
+        Ok(None)
+    }
+
+    /// Opens a thread from a message for Lumi to reply in, returning the message as if it had been
+    /// sent in the thread
+    async fn open_thread(
+        &self,
+        ctx: &Context,
+        msg: &SerenityMessage,
+    ) -> eyre::Result<SerenityMessage> {
+        let archive = self.config.read().await.discord.thread_archive_duration;
+        let thread = msg
+            .channel_id
+            .create_thread_from_message(
+                ctx,
+                msg.id,
+                CreateThread::new(thread_name(ctx, msg)).auto_archive_duration(archive),
+            )
+            .await?;
+        self.db
+            .acquire()
+            .await?
+            .conn()
+            .ensure_thread(thread.id, msg.channel_id)
+            .await?;
+        let mut msg = msg.clone();
+        msg.channel_id = thread.id;
+        Ok(msg)
+    }
+
+    async fn dispatch(&self, ctx: &Context, command: &CommandInteraction) -> eyre::Result<()> {
+        match command.data.name.as_str() {
+            "reload" => commands::reload::run(ctx, command, self).await,
+            "reset_context" => commands::reset_context::run(ctx, command, self).await,
+            "system_prompt" => commands::system_prompt::run(ctx, command, self).await,
+            "chat_mode" => commands::chat_mode::run(ctx, command, self).await,
+            "permissions" => commands::permissions::run(ctx, command, self).await,
+            "model" => commands::model::run(ctx, command, self).await,
+            "server_settings" => commands::server_settings::run(ctx, command, self).await,
+            "usage" => commands::usage::run(ctx, command, self).await,
+            "why" => commands::why::run(ctx, command, self).await,
+            "remember" => commands::remember::run(ctx, command, self).await,
+            "memory" => commands::memory::run(ctx, command, self).await,
+            _ => {
+                let response: CreateInteractionResponse = CreateInteractionResponse::Message(
+                    CreateInteractionResponseMessage::new().content("Unknown command :("),
+                );
+                command
+                    .create_response(&ctx.http, response)
+                    .await
+                    .map_err(Into::into)
+            }
+        }
+    }
+}
+
+// Notices can't be ephemeral outside of interactions, so they clean up after themselves instead
+async fn send_notice(ctx: &Context, msg: &SerenityMessage, notice: String) {
+    let notice = match msg
+        .channel_id
+        .send_message(
+            ctx,
+            CreateMessage::new()
+                .content(format!("-# {notice}"))
+                .reference_message(msg)
+                .allowed_mentions(CreateAllowedMentions::new()),
+        )
+        .await
+    {
+        Ok(notice) => notice,
+        Err(err) => {
+            println!("Error sending message: {err:?}");
+            return;
+        }
+    };
+    let ctx = ctx.to_owned();
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        if let Err(err) = notice.delete(&ctx).await {
+            println!("Error deleting message: {err:?}");
+        }
+    });
+}
+
+// Thread names are the start of the message without mentions, Discord allows up to 100 characters
+fn thread_name(ctx: &Context, msg: &SerenityMessage) -> String {
+    let content = msg.content_safe(ctx);
+    let words = content
+        .split_whitespace()
+        .filter(|word| !word.starts_with('@'))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let mut name = words.chars().take(80).collect::<String>();
+    if name.len() < words.len() {
+        name.push_str("...");
+    }
+    if name.is_empty() {
+        name = format!("Chat with {}", msg.author.display_name());
+    }
+    name
+}
+
+/// The channel a thread was started in, `None` for anything that isn't a thread
+async fn thread_parent(ctx: &Context, channel: ChannelId) -> Option<ChannelId> {
+    match channel.to_channel(ctx).await {
+        Ok(Channel::Guild(channel)) if channel.thread_metadata.is_some() => channel.parent_id,
+        Ok(_) => None,
+        Err(err) => {
+            println!("Error fetching channel: {err:?}");
+            None
+        }
+    }
+}
+
+async fn delete_messages(db: &db::Database, ids: &[MessageId]) -> eyre::Result<()> {
+    let mut transaction = db.begin().await?;
+    for id in ids {
+        transaction.conn().delete_message(*id).await?;
     }
+    transaction.commit().await?;
+    Ok(())
 }
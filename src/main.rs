@@ -1,26 +1,56 @@
-use openai_api_rs::v1::{api::OpenAIClientBuilder, chat_completion::Reasoning};
+use std::collections::{BTreeMap, HashMap};
+
+use openai_api_rs::v1::chat_completion::Reasoning;
 use serde::Deserialize;
 use serenity::all::*;
-use sqlx::postgres::PgPoolOptions;
-use tokio::sync::{Mutex, RwLock};
+use tokio::sync::RwLock;
 
-use crate::handler::Handler;
+use crate::{
+    chat::{
+        client::LlmClient, debounce::Debouncer, limits::RateLimiter, locks::ChannelLocks,
+        tokens::Heuristic, tools::Registry,
+    },
+    db::Database,
+    handler::Handler,
+};
 
 pub mod chat;
 pub mod commands;
 pub mod db;
 pub mod handler;
 
+pub const OPENROUTER_ENDPOINT: &str = "https://openrouter.ai/api/v1/";
+
 #[derive(Deserialize)]
 pub struct Config {
     pub discord: ConfigDiscord,
     pub openrouter: ConfigOpenrouter,
     pub database: ConfigDatabase,
+    #[serde(default)]
+    pub limits: ConfigLimits,
 }
 
 #[derive(Deserialize)]
 pub struct ConfigDiscord {
     pub bot_token: String,
+    #[serde(default)]
+    pub regenerate_on_edit: bool,
+    #[serde(default)]
+    pub owners: Vec<u64>,
+    #[serde(default = "default_debounce_interval")]
+    pub debounce_interval: u64,
+    #[serde(default)]
+    pub thread_parent_context: usize,
+    #[serde(default = "default_thread_archive_duration")]
+    pub thread_archive_duration: AutoArchiveDuration,
+}
+
+fn default_debounce_interval() -> u64 {
+    2500
+}
+
+fn default_thread_archive_duration() -> AutoArchiveDuration {
+    AutoArchiveDuration::OneHour
 }
 
 #[derive(Deserialize)]
@@ -28,21 +58,196 @@ pub struct ConfigOpenrouter {
     pub api_key: String,
     pub chat: ConfigModel,
     pub social: ConfigModel,
+    pub summary: Option<ConfigModel>,
+    pub embeddings: Option<ConfigEmbeddings>,
     pub window_threshold: usize,
     pub max_attempts: isize,
+    #[serde(default)]
+    pub reply_threshold: f64,
+    #[serde(default = "default_stream_interval")]
+    pub stream_interval: u64,
+    #[serde(default = "default_max_tool_iterations")]
+    pub max_tool_iterations: usize,
+    #[serde(default = "default_max_concurrent_requests")]
+    pub max_concurrent_requests: usize,
+    #[serde(default = "default_max_channel_requests")]
+    pub max_channel_requests: usize,
+    #[serde(default)]
+    pub prices: HashMap<String, ConfigPrice>,
+    #[serde(default)]
+    pub models: BTreeMap<String, ConfigCapabilities>,
+}
+
+fn default_stream_interval() -> u64 {
+    1500
+}
+
+fn default_max_tool_iterations() -> usize {
+    4
+}
+
+fn default_max_concurrent_requests() -> usize {
+    8
+}
+
+fn default_max_channel_requests() -> usize {
+    2
 }
 
 #[derive(Deserialize)]
 pub struct ConfigModel {
     pub model: String,
     pub reasoning: Option<Reasoning>,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub max_tokens: Option<i64>,
+    #[serde(flatten)]
+    pub capabilities: ConfigCapabilities,
+    #[serde(default)]
+    pub fallbacks: Vec<String>,
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+    #[serde(default = "default_backoff")]
+    pub backoff: u64,
+}
+
+/// What a model supports and how much context it accepts
+#[derive(Deserialize)]
+pub struct ConfigCapabilities {
+    #[serde(default)]
+    pub vision: bool,
+    #[serde(default)]
+    pub stream: bool,
+    #[serde(default)]
+    pub tools: bool,
+    pub max_context_tokens: Option<usize>,
+    #[serde(default = "default_completion_headroom")]
+    pub completion_headroom: usize,
+}
+
+fn default_retries() -> u32 {
+    2
+}
+
+fn default_backoff() -> u64 {
+    1000
+}
+
+fn default_completion_headroom() -> usize {
+    4096
+}
+
+impl ConfigCapabilities {
+    /// Tokens the prompt may take up, leaving the headroom free for the completion
+    pub fn context_budget(&self) -> Option<usize> {
+        self.max_context_tokens
+            .map(|max| max.saturating_sub(self.completion_headroom))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ConfigEmbeddings {
+    pub model: String,
+    /// Any OpenAI compatible API, OpenRouter when unset
+    pub endpoint: Option<String>,
+    /// The OpenRouter API key when unset
+    pub api_key: Option<String>,
+    #[serde(default = "default_recall")]
+    pub recall: usize,
+    #[serde(default = "default_min_similarity")]
+    pub min_similarity: f32,
+}
+
+fn default_recall() -> usize {
+    5
+}
+
+fn default_min_similarity() -> f32 {
+    0.5
+}
+
+/// USD per million tokens
+#[derive(Deserialize)]
+pub struct ConfigPrice {
+    pub prompt: f64,
+    pub completion: f64,
+}
+
+impl ConfigOpenrouter {
+    /// Whether channels and guilds may switch to a model
+    pub fn allows_model(&self, model: &str) -> bool {
+        model == self.chat.model || self.models.contains_key(model)
+    }
+
+    /// The capabilities of a channel's chat model, the chat model's own unless it switched to one
+    /// of the allowed models
+    pub fn chat_capabilities(&self, model: Option<&str>) -> &ConfigCapabilities {
+        model
+            .and_then(|model| self.models.get(model))
+            .unwrap_or(&self.chat.capabilities)
+    }
+
+    /// Estimated cost of a model's usage in USD, if the model has a price
+    pub fn cost(&self, total: &db::UsageTotal) -> Option<f64> {
+        self.prices.get(&total.model).map(|price| {
+            (total.prompt_tokens as f64 * price.prompt
+                + total.completion_tokens as f64 * price.completion)
+                / 1_000_000.0
+        })
+    }
+}
+
+#[derive(Deserialize, Default)]
+pub struct ConfigLimits {
+    pub user_messages_per_minute: Option<u32>,
+    pub channel_completions_per_hour: Option<u64>,
+    pub guild_daily_tokens: Option<u64>,
+    pub guild_daily_cost: Option<f64>,
+}
+
+impl ConfigLimits {
+    /// The limits in effect for a guild, guilds can only tighten the limits set in the config
+    pub fn for_guild(&self, guild: Option<&db::Guild>) -> ConfigLimits {
+        let Some(guild) = guild else {
+            return ConfigLimits { ..*self };
+        };
+        ConfigLimits {
+            user_messages_per_minute: tightest(
+                self.user_messages_per_minute,
+                guild.user_messages_per_minute,
+            ),
+            channel_completions_per_hour: tightest(
+                self.channel_completions_per_hour,
+                guild.channel_completions_per_hour,
+            ),
+            guild_daily_tokens: tightest(self.guild_daily_tokens, guild.daily_tokens),
+            guild_daily_cost: tightest(self.guild_daily_cost, guild.daily_cost),
+        }
+    }
+}
+
+fn tightest<T: PartialOrd>(config: Option<T>, guild: Option<T>) -> Option<T> {
+    match (config, guild) {
+        (Some(config), Some(guild)) if guild < config => Some(guild),
+        (config, guild) => config.or(guild),
+    }
 }
 
 #[derive(Deserialize)]
 pub struct ConfigDatabase {
+    #[serde(default)]
+    pub backend: ConfigDatabaseBackend,
     pub url: String,
 }
 
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigDatabaseBackend {
+    #[default]
+    Postgres,
+    Sqlite,
+}
+
 #[tokio::main]
 async fn main() -> eyre::Result<()> {
     let config: Config = toml::from_str(
@@ -51,29 +256,31 @@ async fn main() -> eyre::Result<()> {
             .expect("Failed to read config file"),
     )
     .expect("Failed to parse config file");
-    let openai = OpenAIClientBuilder::new()
-        .with_api_key(&config.openrouter.api_key)
-        .with_endpoint("https://openrouter.ai/api/v1/")
-        .build()
-        .expect("Failed to build OpenAI client");
     let bot_token = config.discord.bot_token.clone();
-    let db = PgPoolOptions::new()
-        .connect(&config.database.url)
+    let db = Database::connect(&config.database)
         .await
-        .expect("Failed to connect to postgres database");
-    for stmt in include_str!("../setup.sql").split("-- break") {
-        let stmt = stmt.trim();
-        if !stmt.is_empty() {
-            sqlx::query(stmt)
-                .execute(&db)
-                .await
-                .expect("Database setup script failed");
+        .expect("Failed to connect to database");
+    let applied = db.migrate().await.expect("Database migrations failed");
+    for migration in &applied {
+        println!("Applied migration {}", migration.name);
+    }
+    // `lumi migrate` only brings the database schema up to date
+    if std::env::args().nth(1).as_deref() == Some("migrate") {
+        if applied.is_empty() {
+            println!("Database is up to date");
         }
+        return Ok(());
     }
+    let openai = LlmClient::new(&config.openrouter, db.clone());
 
     let handler = Handler {
         config: RwLock::new(config),
-        openai: Mutex::new(openai),
+        openai,
+        tools: Registry::default(),
+        estimator: Box::new(Heuristic),
+        channel_locks: ChannelLocks::default(),
+        debouncer: Debouncer::default(),
+        user_limiter: RateLimiter::default(),
         db,
     };
 